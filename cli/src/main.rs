@@ -0,0 +1,380 @@
+use std::io::{self, BufRead, IsTerminal};
+use std::process;
+
+use rustyline::DefaultEditor;
+use rustyline::error::ReadlineError;
+use serde_json::Value;
+
+use somedb_client::{Client, Command, Data, Response, Status, Transaction};
+use somedb_client::utils::{read_usize, read_string};
+
+const HISTORY_FILE: &str = ".somedb_history";
+
+//how a command takes its arguments after its name
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Shape {
+    Nothing,
+    Path,
+    Value,
+    Link,
+    Json,
+    By,
+    Compare,
+    //commands the shell has no shape for, every field of the transaction in order
+    Raw
+}
+
+fn shape(cmd: Command) -> Shape {
+    match cmd {
+        Command::Create | Command::CreateList | Command::Gc | Command::Compact | Command::Snapshot | Command::Ping => Shape::Nothing,
+        Command::Get | Command::GetRaw | Command::Len | Command::Delete | Command::Unlink | Command::RemoveAt => Shape::Path,
+        Command::Set | Command::Append | Command::InsertAt => Shape::Value,
+        Command::Link => Shape::Link,
+        Command::PutJson => Shape::Json,
+        Command::Incr | Command::Decr => Shape::By,
+        Command::CompareAndSet => Shape::Compare,
+        _ => Shape::Raw
+    }
+}
+
+fn usage(shape: Shape) -> &'static str {
+    match shape {
+        Shape::Nothing => "",
+        Shape::Path => "<id> [path]",
+        Shape::Value => "<id> <path> <value|@id>",
+        Shape::Link => "<id> <path> <id>",
+        Shape::Json => "<json>",
+        Shape::By => "<id> <path> [by]",
+        Shape::Compare => "<id> <path> <expected> <value>",
+        Shape::Raw => "<id> <key> <value> <id>"
+    }
+}
+
+//every command the client knows, so commands added later can be used without changing the shell
+fn commands() -> Vec<Command> {
+    (0..=u8::MAX).filter_map(|n| Command::from(n).ok()).collect()
+}
+
+fn command_name(cmd: Command) -> String {
+    format!("{:?}", cmd).to_lowercase()
+}
+
+fn command_named(name: &str) -> Option<Command> {
+    commands().into_iter().find(|cmd| command_name(*cmd) == name.to_lowercase())
+}
+
+//splits a line on spaces, text in double quotes is kept together along with its quotes so a value can tell it is text
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for c in line.chars() {
+        if quoted {
+            token.push(c);
+            if escaped {
+                escaped = false;
+            }else if c == '\\' {
+                escaped = true;
+            }else if c == '"' {
+                quoted = false;
+            }
+        }else if c.is_whitespace() {
+            if !token.is_empty() {
+                tokens.push(std::mem::take(&mut token));
+            }
+        }else {
+            quoted = c == '"';
+            token.push(c);
+        }
+    }
+
+    if quoted {
+        return Err(String::from("unterminated quote"));
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+
+    Ok(tokens)
+}
+
+fn parse_id(token: &str) -> Result<usize, String> {
+    token.parse::<usize>().map_err(|_| format!("{} is not an id", token))
+}
+
+fn parse_key(token: Option<&String>) -> String {
+    match token {
+        Some(token) if token.starts_with('"') => serde_json::from_str(token).unwrap_or_else(|_| token.to_owned()),
+        Some(token) => token.to_owned(),
+        None => String::new()
+    }
+}
+
+//json literals keep their type (55, -3, 0.5, true, null, "quoted text"), anything else is taken as text
+fn parse_value(token: &str) -> Data {
+    match serde_json::from_str::<Value>(token) {
+        Ok(Value::Object(_)) | Ok(Value::Array(_)) | Err(_) => Data::from(token),
+        Ok(json) => Data::from(&json)
+    }
+}
+
+fn parse(line: &str) -> Result<Transaction, String> {
+    let (name, rest) = match line.find(char::is_whitespace) {
+        Some(i) => (&line[..i], line[i..].trim()),
+        None => (line, "")
+    };
+
+    let cmd = match command_named(name) {
+        Some(cmd) => cmd,
+        None => return Err(format!("unknown command {}, try help", name))
+    };
+
+    //the document is the rest of the line as it is
+    if shape(cmd) == Shape::Json {
+        return Ok(Transaction::new(cmd, 0, String::new(), Data::Str(rest.to_owned()), 0));
+    }
+
+    let args = tokenize(rest)?;
+    let t = match (shape(cmd), args.len()) {
+        (Shape::Nothing, 0) => Transaction::new(cmd, 0, String::new(), Data::Null, 0),
+        (Shape::Path, 1) | (Shape::Path, 2) => Transaction::new(cmd, parse_id(&args[0])?, parse_key(args.get(1)), Data::Null, 0),
+        (Shape::Value, 3) => match args[2].strip_prefix('@') {
+            //setting a key to another object is a link, the other object of a set is not something clients pick
+            Some(id) if cmd == Command::Set => Transaction::new(Command::Link, parse_id(&args[0])?, parse_key(args.get(1)), Data::Null, parse_id(id)?),
+            Some(id) => Transaction::new(cmd, parse_id(&args[0])?, parse_key(args.get(1)), Data::Null, parse_id(id)?),
+            None => Transaction::new(cmd, parse_id(&args[0])?, parse_key(args.get(1)), parse_value(&args[2]), 0)
+        },
+        (Shape::Link, 3) => Transaction::new(cmd, parse_id(&args[0])?, parse_key(args.get(1)), Data::Null, parse_id(&args[2])?),
+        (Shape::By, 2) | (Shape::By, 3) => {
+            let by = args.get(2).map(|by| parse_value(by)).unwrap_or(Data::Null);
+            Transaction::new(cmd, parse_id(&args[0])?, parse_key(args.get(1)), by, 0)
+        },
+        (Shape::Compare, 4) => Transaction::compare_and_set(parse_id(&args[0])?, parse_key(args.get(1)), parse_value(&args[2]), parse_value(&args[3])),
+        (Shape::Raw, 4) => Transaction::new(cmd, parse_id(&args[0])?, parse_key(args.get(1)), parse_value(&args[2]), parse_id(&args[3])?),
+        (shape, _) => return Err(format!("usage: {} {}", command_name(cmd), usage(shape)))
+    };
+
+    Ok(t)
+}
+
+//objects and lists come back as json text, they are printed spread over lines
+fn pretty(data: &Data) -> String {
+    match data {
+        Data::Str(s) if s.starts_with('{') || s.starts_with('[') => match serde_json::from_str::<Value>(s) {
+            Ok(json) => serde_json::to_string_pretty(&json).unwrap_or_else(|_| s.to_owned()),
+            Err(_) => data.to_json()
+        },
+        _ => data.to_json()
+    }
+}
+
+fn show(cmd: Command, resp: &Response) -> Result<String, String> {
+    if resp.status == Status::Error {
+        return Err(read_string(&resp.data));
+    }
+    if resp.data.len() < 8 {
+        return Err(String::from("response too short"));
+    }
+
+    let id = read_usize(&resp.data[..8]);
+    let rest = &resp.data[8..];
+    match resp.status {
+        Status::Ok => {},
+        Status::Fail => return Err(String::from("not found")),
+        Status::Conflict => return match Data::from_bytes(rest) {
+            Ok(current) => Err(format!("conflict, the key holds {} ({})", current.to_json(), id)),
+            Err(e) => Err(e.to_string())
+        },
+        Status::Error => {}
+    };
+
+    match cmd {
+        Command::Get | Command::GetRaw | Command::Len | Command::Incr | Command::Decr => match Data::from_bytes(rest) {
+            Ok(data) => Ok(format!("({}) {}", id, pretty(&data))),
+            Err(e) => Err(e.to_string())
+        },
+        Command::Create | Command::CreateList | Command::PutJson | Command::CompareAndSet => Ok(format!("({})", id)),
+        Command::Gc => Ok(format!("removed {} nodes", id)),
+        _ if shape(cmd) == Shape::Raw => match Data::from_bytes(rest) {
+            Ok(data) => Ok(format!("({}) {}", id, pretty(&data))),
+            Err(_) => Ok(format!("({}) {}", id, read_string(rest)))
+        },
+        _ => Ok(read_string(rest))
+    }
+}
+
+fn help() -> String {
+    let mut lines = vec![
+        String::from("paths are keys separated by dots (achievements.0.title). values are json literals (55, -3, 0.5,"),
+        String::from("true, null, \"quoted text\") or text, @<id> in place of a value links the object with that id"),
+        String::new()
+    ];
+    for cmd in commands() {
+        lines.push(format!("  {} {}", command_name(cmd), usage(shape(cmd))));
+    }
+    lines.push(String::from("  help"));
+    lines.push(String::from("  exit"));
+    lines.join("\n")
+}
+
+//what is left to do once a line has run
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Print(String),
+    Nothing,
+    Exit
+}
+
+//runs one line, the same way whether it was typed, passed with -c or read from a script
+fn run(db: &Client, line: &str) -> Result<Outcome, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(Outcome::Nothing);
+    }
+    if line == "exit" || line == "quit" {
+        return Ok(Outcome::Exit);
+    }
+    if line == "help" {
+        return Ok(Outcome::Print(help()));
+    }
+
+    let t = parse(line)?;
+    let resp = db.execute(&t).map_err(|e| e.to_string())?;
+    show(t.cmd, &resp).map(Outcome::Print)
+}
+
+//reads commands from the terminal with line editing and history kept in the home directory
+fn interactive(db: &Client, addr: &str) {
+    let mut editor = match DefaultEditor::new() {
+        Ok(e) => e,
+        Err(e) => panic!("couldn't open the terminal: {}", e)
+    };
+
+    let history = match std::env::var("HOME") {
+        Ok(home) => format!("{}/{}", home, HISTORY_FILE),
+        Err(_) => HISTORY_FILE.to_owned()
+    };
+    let _ = editor.load_history(history.as_str());
+
+    println!("connected to {}, type help for the commands", addr);
+    loop {
+        let line = match editor.readline("somedb> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                println!("Error: {}", e);
+                break;
+            }
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let _ = editor.add_history_entry(line);
+
+        match run(db, line) {
+            Ok(Outcome::Print(out)) => println!("{}", out),
+            Ok(Outcome::Nothing) => {},
+            Ok(Outcome::Exit) => break,
+            Err(e) => println!("error: {}", e)
+        };
+    }
+
+    if let Err(e) = editor.save_history(history.as_str()) {
+        println!("couldn't save history to {}: {}", history, e);
+    }
+}
+
+//for scripts, stops at the first command that fails and exits with 1, or at exit without running the rest
+fn batch<I: Iterator<Item = String>>(db: &Client, lines: I) {
+    for line in lines {
+        match run(db, line.as_str()) {
+            Ok(Outcome::Print(out)) => println!("{}", out),
+            Ok(Outcome::Nothing) => {},
+            Ok(Outcome::Exit) => return,
+            Err(e) => {
+                eprintln!("error: {}: {}", line.trim(), e);
+                process::exit(1);
+            }
+        };
+    }
+}
+
+//somedb-cli [address] [-c <command>]...
+//without -c commands are read from stdin, with line editing when it is a terminal
+fn main() {
+    let mut addr = String::from("localhost:4000");
+    let mut commands: Vec<String> = vec![];
+
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-c" => match args.next() {
+                Some(cmd) => commands.push(cmd),
+                None => {
+                    eprintln!("-c needs a command");
+                    process::exit(2);
+                }
+            },
+            "-h" | "--help" => {
+                println!("usage: somedb-cli [address] [-c <command>]...\n\n{}", help());
+                return;
+            },
+            _ => addr = arg
+        };
+    }
+
+    let db = Client::new(addr.as_str());
+    if !commands.is_empty() {
+        batch(&db, commands.into_iter());
+    }else if io::stdin().is_terminal() {
+        interactive(&db, addr.as_str());
+    }else {
+        batch(&db, io::stdin().lock().lines().map_while(Result::ok));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_lines() {
+        assert_eq!(tokenize(r#"set 1 name "tim \"the\" dev""#).unwrap(), vec!["set", "1", "name", r#""tim \"the\" dev""#]);
+        assert!(tokenize(r#"set 1 name "tim"#).is_err());
+
+        assert_eq!(command_named("CompareAndSet"), Some(Command::CompareAndSet));
+        assert_eq!(command_named("nope"), None);
+
+        assert_eq!(parse_value("55"), Data::U64(55));
+        assert_eq!(parse_value("-3"), Data::I64(-3));
+        assert_eq!(parse_value("true"), Data::Bool(true));
+        assert_eq!(parse_value("tim"), Data::from("tim"));
+        assert_eq!(parse_value(r#""55""#), Data::from("55"));
+
+        let t = parse("set 1 stats.clicks 55").unwrap();
+        assert_eq!((t.cmd, t.obj, t.key.as_str(), t.val, t.othr), (Command::Set, 1, "stats.clicks", Data::U64(55), 0));
+
+        let t = parse("set 1 friend @7").unwrap();
+        assert_eq!((t.cmd, t.obj, t.key.as_str(), t.val, t.othr), (Command::Link, 1, "friend", Data::Null, 7));
+
+        let t = parse("append 1 tags @7").unwrap();
+        assert_eq!((t.cmd, t.val, t.othr), (Command::Append, Data::Null, 7));
+
+        let t = parse(r#"putjson {"name": "tim"}"#).unwrap();
+        assert_eq!(t.val, Data::from(r#"{"name": "tim"}"#));
+
+        assert!(parse("get").is_err());
+        assert!(parse("get x").is_err());
+
+        //exit ends a script too, without reaching the server
+        let db = Client::new("localhost:1");
+        assert_eq!(run(&db, "exit"), Ok(Outcome::Exit));
+        assert_eq!(run(&db, " quit "), Ok(Outcome::Exit));
+        assert_eq!(run(&db, "# exit"), Ok(Outcome::Nothing));
+    }
+}
@@ -0,0 +1,199 @@
+use tokio::net::TcpStream;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::Mutex;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use crate::utils::*;
+use crate::error::Error;
+use crate::client::*;
+
+//same commands as Client for use from a tokio runtime. Requests on one client are sent one at a time over
+//a single connection, use more clients for requests that should run side by side
+pub struct AsyncClient {
+    addr: String,
+    //taken out for the length of a request so a request that fails or is dropped halfway leaves no
+    //half read response behind, the next request then connects again
+    stream: Mutex<Option<TcpStream>>
+}
+
+impl AsyncClient {
+    pub fn new(addr: &str) -> AsyncClient {
+        AsyncClient {
+            addr: addr.to_owned(),
+            stream: Mutex::new(None)
+        }
+    }
+
+    async fn connect(&self) -> Result<TcpStream, Error> {
+        let stream = TcpStream::connect(self.addr.as_str()).await?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    async fn send(&self, cmd: Command, obj: usize, key: &str, val: Data, other_obj: usize) -> Result<Vec<u8>, Error> {
+        check(self.request(&Transaction::new(cmd, obj, key.to_owned(), val, other_obj)).await?)
+    }
+
+    async fn send_id(&self, cmd: Command, obj: usize, key: &str, val: Data, other_obj: usize) -> Result<usize, Error> {
+        read_id(&self.send(cmd, obj, key, val, other_obj).await?)
+    }
+
+    async fn send_value(&self, cmd: Command, obj: usize, key: &str, val: Data) -> Result<(usize, Data), Error> {
+        read_value(&self.send(cmd, obj, key, val, 0).await?)
+    }
+
+    pub(crate) async fn request(&self, t: &Transaction) -> Result<Response, Error> {
+        let mut guard = self.stream.lock().await;
+        let mut stream = match guard.take() {
+            Some(stream) => stream,
+            None => self.connect().await?
+        };
+
+        stream.write_all(&frame(t)).await?;
+
+        let mut resp_size = [0u8; 8];
+        stream.read_exact(&mut resp_size).await?;
+
+        let mut data = vec![0u8; read_usize(&resp_size)];
+        stream.read_exact(&mut data).await?;
+
+        *guard = Some(stream);
+        Ok(Response::from(data)?)
+    }
+
+    //checks the server can be reached
+    pub async fn ping(&self) -> Result<(), Error> {
+        self.send(Command::Ping, 0, "", Data::Null, 0).await.map(|_| ())
+    }
+
+    //collects transactions to send as one batch, applied all together or not at all
+    pub fn begin(&self) -> Batch<'_, AsyncClient> {
+        Batch {
+            client: self,
+            transactions: vec![]
+        }
+    }
+
+    pub async fn create(&self) -> Result<usize, Error> {
+        self.send_id(Command::Create, 0, "", Data::Null, 0).await
+    }
+
+    pub async fn put_json(&self, json: &serde_json::Value) -> Result<usize, Error> {
+        self.send_id(Command::PutJson, 0, "", Data::Str(json.to_string()), 0).await
+    }
+
+    //returns the number of nodes that were removed
+    pub async fn gc(&self) -> Result<usize, Error> {
+        self.send_id(Command::Gc, 0, "", Data::Null, 0).await
+    }
+
+    pub async fn compact(&self) -> Result<(), Error> {
+        self.send(Command::Compact, 0, "", Data::Null, 0).await.map(|_| ())
+    }
+
+    pub async fn snapshot(&self) -> Result<(), Error> {
+        self.send(Command::Snapshot, 0, "", Data::Null, 0).await.map(|_| ())
+    }
+
+    pub async fn set<T: Into<Data>>(&self, obj: usize, key: &str, val: T) -> Result<usize, Error> {
+        self.send_id(Command::Set, obj, key, val.into(), 0).await
+    }
+
+    //see Client::compare_and_set
+    pub async fn compare_and_set<E: Into<Data>, T: Into<Data>>(&self, obj: usize, key: &str, expected: E, val: T) -> Result<usize, Error> {
+        let t = Transaction::compare_and_set(obj, key.to_owned(), expected.into(), val.into());
+        read_id(&check(self.request(&t).await?)?)
+    }
+
+    pub async fn compare_and_set_node<T: Into<Data>>(&self, obj: usize, key: &str, expected: usize, val: T) -> Result<usize, Error> {
+        let t = Transaction::compare_and_set_node(obj, key.to_owned(), expected, val.into());
+        read_id(&check(self.request(&t).await?)?)
+    }
+
+    pub async fn incr<T: Into<Data>>(&self, obj: usize, key: &str, by: T) -> Result<(usize, Data), Error> {
+        self.send_value(Command::Incr, obj, key, by.into()).await
+    }
+
+    pub async fn decr<T: Into<Data>>(&self, obj: usize, key: &str, by: T) -> Result<(usize, Data), Error> {
+        self.send_value(Command::Decr, obj, key, by.into()).await
+    }
+
+    pub async fn get(&self, obj: usize, key: &str) -> Result<(usize, Data), Error> {
+        self.send_value(Command::Get, obj, key, Data::Null).await
+    }
+
+    pub async fn get_str(&self, obj: usize, key: &str) -> Result<(usize, String), Error> {
+        let (id, data) = self.get(obj, key).await?;
+        Ok((id, data.to_string()))
+    }
+
+    pub async fn get_usize(&self, obj: usize, key: &str) -> Result<(usize, Option<usize>), Error> {
+        Ok(read_number(self.get(obj, key).await?))
+    }
+
+    pub async fn get_obj(&self, obj: usize) -> Result<(usize, String), Error> {
+        self.get_str(obj, "").await
+    }
+
+    pub async fn link(&self, obj: usize, key: &str, othr: usize) -> Result<usize, Error> {
+        self.send_id(Command::Link, obj, key, Data::Null, othr).await
+    }
+
+    pub async fn delete(&self, obj: usize, key: &str) -> Result<usize, Error> {
+        self.send_id(Command::Delete, obj, key, Data::Null, 0).await
+    }
+
+    pub async fn unlink(&self, obj: usize, key: &str) -> Result<usize, Error> {
+        self.send_id(Command::Unlink, obj, key, Data::Null, 0).await
+    }
+
+    pub async fn create_list(&self) -> Result<usize, Error> {
+        self.send_id(Command::CreateList, 0, "", Data::Null, 0).await
+    }
+
+    pub async fn append<T: Into<Data>>(&self, obj: usize, key: &str, val: T) -> Result<usize, Error> {
+        self.send_id(Command::Append, obj, key, val.into(), 0).await
+    }
+
+    pub async fn append_link(&self, obj: usize, key: &str, othr: usize) -> Result<usize, Error> {
+        self.send_id(Command::Append, obj, key, Data::Null, othr).await
+    }
+
+    pub async fn insert_at<T: Into<Data>>(&self, obj: usize, key: &str, val: T) -> Result<usize, Error> {
+        self.send_id(Command::InsertAt, obj, key, val.into(), 0).await
+    }
+
+    pub async fn insert_link_at(&self, obj: usize, key: &str, othr: usize) -> Result<usize, Error> {
+        self.send_id(Command::InsertAt, obj, key, Data::Null, othr).await
+    }
+
+    pub async fn remove_at(&self, obj: usize, key: &str) -> Result<usize, Error> {
+        self.send_id(Command::RemoveAt, obj, key, Data::Null, 0).await
+    }
+
+    //see Client::store
+    pub async fn store<T: Serialize>(&self, val: &T) -> Result<usize, Error> {
+        let mut batch = self.begin();
+        batch.store(val)?;
+        let resps = batch.commit().await?;
+        read_id(&resps[0].data)
+    }
+
+    pub async fn load<T: DeserializeOwned>(&self, obj: usize) -> Result<T, Error> {
+        let (_, json) = self.get_obj(obj).await?;
+        Ok(serde_json::from_str(json.as_str())?)
+    }
+
+    pub async fn len(&self, obj: usize, key: &str) -> Result<(usize, Option<usize>), Error> {
+        Ok(read_len(self.send_value(Command::Len, obj, key, Data::Null).await?))
+    }
+}
+
+impl<'a> Batch<'a, AsyncClient> {
+    //see Batch::commit
+    pub async fn commit(self) -> Result<Vec<Response>, Error> {
+        read_batch(&check(self.client.request(&Transaction::batch(&self.transactions)).await?)?)
+    }
+}
@@ -0,0 +1,425 @@
+use std::time::Duration;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use crate::utils::*;
+use crate::error::Error;
+use crate::pool::{Pool, exchange};
+
+pub struct Client {
+    //connections are kept open between calls, one that fails is closed and the next call connects again
+    pool: Pool
+}
+
+impl Client {
+    //a client with a single connection, calls from several threads wait their turn for it
+    pub fn new(addr: &str) -> Client {
+        Client::pooled(addr, 1)
+    }
+
+    //a client that keeps up to size connections open so that many threads can make calls at once
+    pub fn pooled(addr: &str, size: usize) -> Client {
+        Client {
+            pool: Pool::new(addr, size)
+        }
+    }
+
+    //how long a connection can go unused before it is pinged ahead of its next request, 5 seconds by default
+    pub fn check_after(mut self, after: Duration) -> Client {
+        self.pool.check_after = after;
+        self
+    }
+
+    fn send(&self, cmd: Command, obj: usize, key: &str, val: Data, other_obj: usize) -> Result<Vec<u8>, Error> {
+        check(self.request(&Transaction::new(cmd, obj, key.to_owned(), val, other_obj))?)
+    }
+
+    fn send_id(&self, cmd: Command, obj: usize, key: &str, val: Data, other_obj: usize) -> Result<usize, Error> {
+        read_id(&self.send(cmd, obj, key, val, other_obj)?)
+    }
+
+    fn send_value(&self, cmd: Command, obj: usize, key: &str, val: Data) -> Result<(usize, Data), Error> {
+        read_value(&self.send(cmd, obj, key, val, 0)?)
+    }
+
+    fn request(&self, t: &Transaction) -> Result<Response, Error> {
+        let request = frame(t);
+        let (mut stream, reused) = self.pool.take()?;
+
+        let data = match exchange(&mut stream, &request) {
+            Ok(data) => data,
+            Err(e) => {
+                self.pool.close();
+
+                //a connection closed by the server in between requests only shows when it is used. Reads are safe
+                //to send again on a new connection, for writes there is no telling if the server applied them.
+                //the other idle connections are dropped first so the retry does not land on one just as stale
+                match t.cmd {
+                    Command::Get | Command::GetRaw | Command::Len | Command::Ping if reused => {
+                        self.pool.close_idle();
+                        let (mut stream, _) = self.pool.take()?;
+                        match exchange(&mut stream, &request) {
+                            Ok(data) => {
+                                self.pool.put_back(stream);
+                                return Ok(Response::from(data)?);
+                            },
+                            Err(e) => {
+                                self.pool.close();
+                                return Err(Error::Io(e));
+                            }
+                        }
+                    },
+                    _ => return Err(Error::Io(e))
+                }
+            }
+        };
+
+        self.pool.put_back(stream);
+        Ok(Response::from(data)?)
+    }
+
+    //sends any transaction as it is and returns the response whatever its status, for commands without a method here
+    pub fn execute(&self, t: &Transaction) -> Result<Response, Error> {
+        self.request(t)
+    }
+
+    //checks the server can be reached
+    pub fn ping(&self) -> Result<(), Error> {
+        self.send(Command::Ping, 0, "", Data::Null, 0).map(|_| ())
+    }
+
+    //collects transactions to send as one batch, applied all together or not at all
+    pub fn begin(&self) -> Batch<'_> {
+        Batch {
+            client: self,
+            transactions: vec![]
+        }
+    }
+
+    pub fn create(&self) -> Result<usize, Error> {
+        self.send_id(Command::Create, 0, "", Data::Null, 0)
+    }
+
+    pub fn put_json(&self, json: &serde_json::Value) -> Result<usize, Error> {
+        self.send_id(Command::PutJson, 0, "", Data::Str(json.to_string()), 0)
+    }
+
+    //returns the number of nodes that were removed
+    pub fn gc(&self) -> Result<usize, Error> {
+        self.send_id(Command::Gc, 0, "", Data::Null, 0)
+    }
+
+    pub fn compact(&self) -> Result<(), Error> {
+        self.send(Command::Compact, 0, "", Data::Null, 0).map(|_| ())
+    }
+
+    pub fn snapshot(&self) -> Result<(), Error> {
+        self.send(Command::Snapshot, 0, "", Data::Null, 0).map(|_| ())
+    }
+
+    pub fn set<T: Into<Data>>(&self, obj: usize, key: &str, val: T) -> Result<usize, Error> {
+        self.send_id(Command::Set, obj, key, val.into(), 0)
+    }
+
+    //sets the key only if it still holds the expected value (Null for a key that does not exist yet) and returns the
+    //id of the new value. On a conflict the error holds the id and value the key holds now
+    pub fn compare_and_set<E: Into<Data>, T: Into<Data>>(&self, obj: usize, key: &str, expected: E, val: T) -> Result<usize, Error> {
+        self.compare(Transaction::compare_and_set(obj, key.to_owned(), expected.into(), val.into()))
+    }
+
+    //same as compare_and_set but expects the key to still point at the node with the given id
+    pub fn compare_and_set_node<T: Into<Data>>(&self, obj: usize, key: &str, expected: usize, val: T) -> Result<usize, Error> {
+        self.compare(Transaction::compare_and_set_node(obj, key.to_owned(), expected, val.into()))
+    }
+
+    fn compare(&self, t: Transaction) -> Result<usize, Error> {
+        read_id(&check(self.request(&t)?)?)
+    }
+
+    //adds to the number at the key (1 when by is Null), returns the id and value of the new number
+    pub fn incr<T: Into<Data>>(&self, obj: usize, key: &str, by: T) -> Result<(usize, Data), Error> {
+        self.send_value(Command::Incr, obj, key, by.into())
+    }
+
+    pub fn decr<T: Into<Data>>(&self, obj: usize, key: &str, by: T) -> Result<(usize, Data), Error> {
+        self.send_value(Command::Decr, obj, key, by.into())
+    }
+
+    pub fn get(&self, obj: usize, key: &str) -> Result<(usize, Data), Error> {
+        self.send_value(Command::Get, obj, key, Data::Null)
+    }
+
+    pub fn get_str(&self, obj: usize, key: &str) -> Result<(usize, String), Error> {
+        let (id, data) = self.get(obj, key)?;
+        Ok((id, data.to_string()))
+    }
+
+    pub fn get_usize(&self, obj: usize, key: &str) -> Result<(usize, Option<usize>), Error> {
+        Ok(read_number(self.get(obj, key)?))
+    }
+
+    pub fn get_obj(&self, obj: usize) -> Result<(usize, String), Error> {
+        self.get_str(obj, "")
+    }
+
+    pub fn link(&self, obj: usize, key: &str, othr: usize) -> Result<usize, Error> {
+        self.send_id(Command::Link, obj, key, Data::Null, othr)
+    }
+
+    pub fn delete(&self, obj: usize, key: &str) -> Result<usize, Error> {
+        self.send_id(Command::Delete, obj, key, Data::Null, 0)
+    }
+
+    pub fn unlink(&self, obj: usize, key: &str) -> Result<usize, Error> {
+        self.send_id(Command::Unlink, obj, key, Data::Null, 0)
+    }
+
+    pub fn create_list(&self) -> Result<usize, Error> {
+        self.send_id(Command::CreateList, 0, "", Data::Null, 0)
+    }
+
+    pub fn append<T: Into<Data>>(&self, obj: usize, key: &str, val: T) -> Result<usize, Error> {
+        self.send_id(Command::Append, obj, key, val.into(), 0)
+    }
+
+    pub fn append_link(&self, obj: usize, key: &str, othr: usize) -> Result<usize, Error> {
+        self.send_id(Command::Append, obj, key, Data::Null, othr)
+    }
+
+    pub fn insert_at<T: Into<Data>>(&self, obj: usize, key: &str, val: T) -> Result<usize, Error> {
+        self.send_id(Command::InsertAt, obj, key, val.into(), 0)
+    }
+
+    pub fn insert_link_at(&self, obj: usize, key: &str, othr: usize) -> Result<usize, Error> {
+        self.send_id(Command::InsertAt, obj, key, Data::Null, othr)
+    }
+
+    pub fn remove_at(&self, obj: usize, key: &str) -> Result<usize, Error> {
+        self.send_id(Command::RemoveAt, obj, key, Data::Null, 0)
+    }
+
+    //stores any serializable value as an object graph, structs and maps become objects and sequences become lists,
+    //nested ones linked from the property holding them. It is written as one batch, returns the id of the top object
+    pub fn store<T: Serialize>(&self, val: &T) -> Result<usize, Error> {
+        let mut batch = self.begin();
+        batch.store(val)?;
+        let resps = batch.commit()?;
+        read_id(&resps[0].data)
+    }
+
+    //reads the object with the given id and everything linked from it back into any deserializable type
+    pub fn load<T: DeserializeOwned>(&self, obj: usize) -> Result<T, Error> {
+        let (_, json) = self.get_obj(obj)?;
+        Ok(serde_json::from_str(json.as_str())?)
+    }
+
+    pub fn len(&self, obj: usize, key: &str) -> Result<(usize, Option<usize>), Error> {
+        Ok(read_len(self.send_value(Command::Len, obj, key, Data::Null)?))
+    }
+}
+
+//keys are paths on the server, a key with a "." in it would be read as two keys and an empty one as the object itself
+fn check_keys(json: &Value) -> Result<(), Error> {
+    match json {
+        Value::Array(items) => items.iter().try_for_each(check_keys),
+        Value::Object(props) => props.iter().try_for_each(|(key, val)| {
+            if key.is_empty() || key.contains('.') {
+                return Err(Error::Json(serde::ser::Error::custom(format!("key \"{}\" cannot be stored, keys must not be empty or contain \".\"", key))));
+            }
+            check_keys(val)
+        }),
+        _ => Ok(())
+    }
+}
+
+//a request with its size in front, the way the server reads it
+pub(crate) fn frame(t: &Transaction) -> Vec<u8> {
+    let mut data = t.to_bytes();
+    let mut framed = write_usize(data.len());
+    framed.append(&mut data);
+    framed
+}
+
+//the data of a response the server carried out, any other response becomes the matching error
+pub(crate) fn check(resp: Response) -> Result<Vec<u8>, Error> {
+    match resp.status {
+        Status::Ok => Ok(resp.data),
+        Status::Fail => Err(Error::NotFound),
+        Status::Conflict => {
+            let id = read_usize(field(&resp.data, 0, Transaction::UINT_SIZE())?);
+            Err(Error::Conflict(id, Data::from_bytes(&resp.data[Transaction::UINT_SIZE()..])?))
+        },
+        Status::Error => Err(Error::Server(read_string(&resp.data)))
+    }
+}
+
+//data of a command answered with the id of the node it touched
+pub(crate) fn read_id(bytes: &[u8]) -> Result<usize, Error> {
+    Ok(read_usize(field(bytes, 0, Transaction::UINT_SIZE())?))
+}
+
+//data of a command answered with the id and value of a node
+pub(crate) fn read_value(bytes: &[u8]) -> Result<(usize, Data), Error> {
+    Ok((read_id(bytes)?, Data::from_bytes(&bytes[Transaction::UINT_SIZE()..])?))
+}
+
+pub(crate) fn read_number((id, data): (usize, Data)) -> (usize, Option<usize>) {
+    match data {
+        Data::U64(n) => (id, Some(n as usize)),
+        Data::I64(n) if n >= 0 => (id, Some(n as usize)),
+        _ => (id, None)
+    }
+}
+
+pub(crate) fn read_len((id, data): (usize, Data)) -> (usize, Option<usize>) {
+    match data {
+        Data::U64(n) => (id, Some(n as usize)),
+        _ => (id, None)
+    }
+}
+
+//the data of a committed batch holds the framed response of every transaction in it
+pub(crate) fn read_batch(data: &[u8]) -> Result<Vec<Response>, Error> {
+    let mut responses = vec![];
+    let mut offset = 0;
+    while offset < data.len() {
+        let size = read_usize( field(data, offset, Transaction::UINT_SIZE())? );
+        offset += Transaction::UINT_SIZE();
+
+        responses.push( Response::from(field(data, offset, size)?.to_vec())? );
+        offset += size;
+    }
+
+    Ok(responses)
+}
+
+//transactions added to a batch are only sent on commit. Methods that make an object return a stand in id
+//that can be used by the transactions added after it, it is replaced with the real id on the server
+pub struct Batch<'a, C = Client> {
+    pub(crate) client: &'a C,
+    pub(crate) transactions: Vec<Transaction>
+}
+
+impl<'a, C> Batch<'a, C> {
+    fn add(&mut self, cmd: Command, obj: usize, key: &str, val: Data, other_obj: usize) -> usize {
+        self.transactions.push(Transaction::new(cmd, obj, key.to_owned(), val, other_obj));
+        Transaction::BATCH_REF | (self.transactions.len() - 1)
+    }
+
+    pub fn create(&mut self) -> usize {
+        self.add(Command::Create, 0, "", Data::Null, 0)
+    }
+
+    pub fn create_list(&mut self) -> usize {
+        self.add(Command::CreateList, 0, "", Data::Null, 0)
+    }
+
+    pub fn put_json(&mut self, json: &serde_json::Value) -> usize {
+        self.add(Command::PutJson, 0, "", Data::Str(json.to_string()), 0)
+    }
+
+    pub fn set<T: Into<Data>>(&mut self, obj: usize, key: &str, val: T) {
+        self.add(Command::Set, obj, key, val.into(), 0);
+    }
+
+    pub fn compare_and_set<E: Into<Data>, T: Into<Data>>(&mut self, obj: usize, key: &str, expected: E, val: T) {
+        self.transactions.push(Transaction::compare_and_set(obj, key.to_owned(), expected.into(), val.into()));
+    }
+
+    pub fn incr<T: Into<Data>>(&mut self, obj: usize, key: &str, by: T) {
+        self.add(Command::Incr, obj, key, by.into(), 0);
+    }
+
+    pub fn decr<T: Into<Data>>(&mut self, obj: usize, key: &str, by: T) {
+        self.add(Command::Decr, obj, key, by.into(), 0);
+    }
+
+    pub fn link(&mut self, obj: usize, key: &str, othr: usize) {
+        self.add(Command::Link, obj, key, Data::Null, othr);
+    }
+
+    pub fn delete(&mut self, obj: usize, key: &str) {
+        self.add(Command::Delete, obj, key, Data::Null, 0);
+    }
+
+    pub fn unlink(&mut self, obj: usize, key: &str) {
+        self.add(Command::Unlink, obj, key, Data::Null, 0);
+    }
+
+    pub fn append<T: Into<Data>>(&mut self, obj: usize, key: &str, val: T) {
+        self.add(Command::Append, obj, key, val.into(), 0);
+    }
+
+    pub fn append_link(&mut self, obj: usize, key: &str, othr: usize) {
+        self.add(Command::Append, obj, key, Data::Null, othr);
+    }
+
+    pub fn insert_at<T: Into<Data>>(&mut self, obj: usize, key: &str, val: T) {
+        self.add(Command::InsertAt, obj, key, val.into(), 0);
+    }
+
+    pub fn insert_link_at(&mut self, obj: usize, key: &str, othr: usize) {
+        self.add(Command::InsertAt, obj, key, Data::Null, othr);
+    }
+
+    pub fn remove_at(&mut self, obj: usize, key: &str) {
+        self.add(Command::RemoveAt, obj, key, Data::Null, 0);
+    }
+
+    //same as Client::store but as part of the batch, returns the stand in id of the top object
+    pub fn store<T: Serialize>(&mut self, val: &T) -> Result<usize, Error> {
+        match serde_json::to_value(val)? {
+            json @ Value::Object(_) | json @ Value::Array(_) => {
+                //checked before anything is added so a bad key leaves the batch as it was
+                check_keys(&json)?;
+                Ok(self.store_json(&json))
+            },
+            _ => Err(Error::Json(serde::ser::Error::custom("only structs, maps and sequences can be stored as an object")))
+        }
+    }
+
+    fn store_json(&mut self, json: &Value) -> usize {
+        match json {
+            Value::Array(items) => {
+                let list = self.create_list();
+                for item in items {
+                    match item {
+                        Value::Object(_) | Value::Array(_) => {
+                            let id = self.store_json(item);
+                            self.append_link(list, "", id);
+                        },
+                        _ => self.append(list, "", Data::from(item))
+                    };
+                }
+                list
+            },
+            Value::Object(props) => {
+                let obj = self.create();
+                for (key, val) in props {
+                    match val {
+                        Value::Object(_) | Value::Array(_) => {
+                            let id = self.store_json(val);
+                            self.link(obj, key.as_str(), id);
+                        },
+                        _ => self.set(obj, key.as_str(), Data::from(val))
+                    };
+                }
+                obj
+            },
+            _ => panic!("only objects and arrays are stored as nodes")
+        }
+    }
+
+    //drops the batch without sending anything
+    pub fn abort(self) {}
+}
+
+impl<'a> Batch<'a, Client> {
+    //sends the batch and returns the response of every transaction in it, in order. If any of them
+    //fails nothing in the batch is applied and the error of the one that failed is returned instead
+    pub fn commit(self) -> Result<Vec<Response>, Error> {
+        read_batch(&check(self.client.request(&Transaction::batch(&self.transactions))?)?)
+    }
+}
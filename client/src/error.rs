@@ -0,0 +1,62 @@
+use std::fmt;
+use std::io;
+
+use crate::utils::*;
+
+#[derive(Debug)]
+pub enum Error {
+    //the server could not be reached or the connection broke during the request
+    Io(io::Error),
+    //the server answered with something that is not a valid response
+    Protocol(DbError),
+    //the server refused the request, the message says why
+    Server(String),
+    //a key on the path does not exist
+    NotFound,
+    //a compare and set found another value, the id and value the key holds now
+    Conflict(usize, Data),
+    //a value could not be stored as an object or the object read could not be turned into the type asked for
+    Json(serde_json::Error)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "Connection error: {}", e),
+            Error::Protocol(e) => write!(f, "Bad response: {}", e),
+            Error::Server(msg) => write!(f, "Server error: {}", msg),
+            Error::NotFound => write!(f, "Key not found"),
+            Error::Conflict(id, val) => write!(f, "Compare and set found {} ({})", val, id),
+            Error::Json(e) => write!(f, "Json error: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Protocol(e) => Some(e),
+            Error::Json(e) => Some(e),
+            _ => None
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<DbError> for Error {
+    fn from(e: DbError) -> Error {
+        Error::Protocol(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::Json(e)
+    }
+}
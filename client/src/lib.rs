@@ -0,0 +1,18 @@
+extern crate byteorder;
+extern crate serde;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+
+pub mod utils;
+pub mod error;
+pub mod client;
+mod pool;
+#[cfg(feature = "async")]
+pub mod async_client;
+
+pub use client::{Client, Batch};
+pub use error::Error;
+#[cfg(feature = "async")]
+pub use async_client::AsyncClient;
+pub use utils::{Data, Command, Transaction, Response, Status, DbError};
@@ -0,0 +1,137 @@
+use std::net::TcpStream;
+use std::io::{self, Read, Write};
+use std::sync::{Mutex, Condvar};
+use std::time::{Duration, Instant};
+
+use crate::utils::*;
+use crate::error::Error;
+
+//how long a connection can sit unused before it is pinged on the way out of the pool
+pub(crate) const CHECK_AFTER: Duration = Duration::from_secs(5);
+
+//how long the ping of an idle connection may take before the connection is given up on
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+struct PoolState {
+    //connections not in use along with when they were last used
+    idle: Vec<(TcpStream, Instant)>,
+    //connections in use plus the idle ones, never more than size
+    open: usize
+}
+
+//a bounded set of connections to the server shared by the threads of one client. A request takes a connection
+//for its length and puts it back once the response is read, a connection that failed is closed instead
+pub(crate) struct Pool {
+    addr: String,
+    size: usize,
+    pub(crate) check_after: Duration,
+    state: Mutex<PoolState>,
+    //signalled whenever a connection is put back or closed
+    freed: Condvar
+}
+
+impl Pool {
+    pub(crate) fn new(addr: &str, size: usize) -> Pool {
+        Pool {
+            addr: addr.to_owned(),
+            size: size.max(1),
+            check_after: CHECK_AFTER,
+            state: Mutex::new(PoolState { idle: vec![], open: 0 }),
+            freed: Condvar::new()
+        }
+    }
+
+    fn connect(&self) -> Result<TcpStream, Error> {
+        let stream = TcpStream::connect(self.addr.as_str())?;
+        stream.set_nodelay(true)?;
+        Ok(stream)
+    }
+
+    //a connection for one request, waits when all of them are in use. The flag tells if it was used before
+    pub(crate) fn take(&self) -> Result<(TcpStream, bool), Error> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some((mut stream, last_used)) = state.idle.pop() {
+                //a connection that sat unused may have been closed by the server, say by a restart
+                if last_used.elapsed() < self.check_after {
+                    return Ok((stream, true));
+                }
+
+                drop(state);
+                if ping(&mut stream).is_ok() {
+                    return Ok((stream, true));
+                }
+
+                state = self.state.lock().unwrap();
+                state.open -= 1;
+                continue;
+            }
+
+            if state.open < self.size {
+                state.open += 1;
+                drop(state);
+
+                return match self.connect() {
+                    Ok(stream) => Ok((stream, false)),
+                    Err(e) => {
+                        self.close();
+                        Err(e)
+                    }
+                };
+            }
+
+            state = self.freed.wait(state).unwrap();
+        }
+    }
+
+    pub(crate) fn put_back(&self, stream: TcpStream) {
+        let mut state = self.state.lock().unwrap();
+        state.idle.push((stream, Instant::now()));
+        self.freed.notify_one();
+    }
+
+    //for a connection that failed, it is dropped rather than put back
+    pub(crate) fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.open -= 1;
+        self.freed.notify_one();
+    }
+
+    //drops every idle connection, once one turned out closed by the server the others most likely are too
+    pub(crate) fn close_idle(&self) {
+        let mut state = self.state.lock().unwrap();
+        let closed = state.idle.len();
+        state.idle.clear();
+        state.open -= closed;
+        self.freed.notify_all();
+    }
+}
+
+//sends a framed request and reads the response it gets
+pub(crate) fn exchange(stream: &mut TcpStream, request: &[u8]) -> Result<Vec<u8>, io::Error> {
+    stream.write_all(request)?;
+
+    let mut resp_size = [0u8; 8];
+    stream.read_exact(&mut resp_size)?;
+
+    let mut data = vec![0u8; read_usize(&resp_size)];
+    stream.read_exact(&mut data)?;
+    Ok(data)
+}
+
+//a server that is up but not answering would otherwise hold the request up for good, the timeouts are
+//lifted again once the connection is known to work
+fn ping(stream: &mut TcpStream) -> Result<(), Error> {
+    stream.set_read_timeout(Some(PING_TIMEOUT))?;
+    stream.set_write_timeout(Some(PING_TIMEOUT))?;
+
+    let t = Transaction::new(Command::Ping, 0, String::new(), Data::Null, 0);
+    let data = exchange(stream, &crate::client::frame(&t))?;
+
+    stream.set_read_timeout(None)?;
+    stream.set_write_timeout(None)?;
+    match Response::from(data)?.status {
+        Status::Ok => Ok(()),
+        status => Err(Error::Protocol(DbError::Malformed(format!("ping answered with {:?}", status))))
+    }
+}
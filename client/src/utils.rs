@@ -0,0 +1,668 @@
+use byteorder::{ReadBytesExt, WriteBytesExt, LittleEndian};
+use std::io::Cursor;
+use std::fmt;
+use std::convert::TryFrom;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+
+const UINT_SIZE: u8 = 8;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum DbError {
+    NoSuchObject(usize),
+    NotAnObject(usize),
+    NotAList(usize),
+    BadIndex(usize, String),
+    IdTaken(usize),
+    StillLinked(usize, usize),
+    Cycle(usize, usize),
+    BadKey(String),
+    BadJson(String),
+    UnsupportedCommand(u8),
+    UnsupportedStatus(u8),
+    NotBatchable(Command),
+    NotANumber(String),
+    Overflow(String),
+    Malformed(String),
+    Disk(String)
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DbError::NoSuchObject(id) => write!(f, "No such object ({})", id),
+            DbError::NotAnObject(id) => write!(f, "Id {} links to a value", id),
+            DbError::NotAList(id) => write!(f, "Id {} is not a list", id),
+            DbError::BadIndex(id, key) => write!(f, "Key {} is not an index of list ({})", key, id),
+            DbError::IdTaken(id) => write!(f, "Id {} is already taken", id),
+            DbError::StillLinked(id, cnt) => write!(f, "Id {} is still linked from {} places", id, cnt),
+            DbError::Cycle(id, under) => write!(f, "Linking {} under {} would make a cycle", id, under),
+            DbError::BadKey(key) => write!(f, "Key \"{}\" can not be used, keys must not be empty or contain \".\"", key),
+            DbError::BadJson(e) => write!(f, "Invalid json: {}", e),
+            DbError::UnsupportedCommand(n) => write!(f, "Unsupported command {}", n),
+            DbError::UnsupportedStatus(n) => write!(f, "Unsupported status {}", n),
+            DbError::NotBatchable(cmd) => write!(f, "{:?} can not be part of a batch", cmd),
+            DbError::NotANumber(what) => write!(f, "{} is not a number", what),
+            DbError::Overflow(key) => write!(f, "Key {} would no longer fit its number type", key),
+            DbError::Malformed(e) => write!(f, "Malformed data: {}", e),
+            DbError::Disk(e) => write!(f, "Couldn't write the database file: {}", e)
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Command {
+    Create,
+    Set,
+    Get,
+    Link,
+    GetRaw,
+    Delete,
+    Unlink,
+    Gc,
+    Compact,
+    CreateList,
+    Append,
+    InsertAt,
+    RemoveAt,
+    Len,
+    PutJson,
+    Batch,
+    CompareAndSet,
+    Incr,
+    Decr,
+    Snapshot,
+    Ping
+}
+
+impl<'de> Deserialize<'de> for Command {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+    {
+        let s = u8::deserialize(deserializer)?;
+        Command::from(s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl Serialize for Command {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+    {
+        serializer.serialize_u8(Command::to_u8(self))
+    }
+}
+
+impl Command {
+    pub fn from(n: u8) -> Result<Command, DbError> {
+        match n {
+            0 => Ok(Command::Create),
+            1 => Ok(Command::Set),
+            2 => Ok(Command::Get),
+            3 => Ok(Command::Link),
+            4 => Ok(Command::GetRaw),
+            5 => Ok(Command::Delete),
+            6 => Ok(Command::Unlink),
+            7 => Ok(Command::Gc),
+            8 => Ok(Command::Compact),
+            9 => Ok(Command::CreateList),
+            10 => Ok(Command::Append),
+            11 => Ok(Command::InsertAt),
+            12 => Ok(Command::RemoveAt),
+            13 => Ok(Command::Len),
+            14 => Ok(Command::PutJson),
+            15 => Ok(Command::Batch),
+            16 => Ok(Command::CompareAndSet),
+            17 => Ok(Command::Incr),
+            18 => Ok(Command::Decr),
+            19 => Ok(Command::Snapshot),
+            20 => Ok(Command::Ping),
+            _=> Err(DbError::UnsupportedCommand(n))
+        }
+    }
+
+    pub fn to_u8(c: &Command) -> u8 {
+        match c {
+            Command::Create => 0,
+            Command::Set => 1,
+            Command::Get => 2,
+            Command::Link => 3,
+            Command::GetRaw => 4,
+            Command::Delete => 5,
+            Command::Unlink => 6,
+            Command::Gc => 7,
+            Command::Compact => 8,
+            Command::CreateList => 9,
+            Command::Append => 10,
+            Command::InsertAt => 11,
+            Command::RemoveAt => 12,
+            Command::Len => 13,
+            Command::PutJson => 14,
+            Command::Batch => 15,
+            Command::CompareAndSet => 16,
+            Command::Incr => 17,
+            Command::Decr => 18,
+            Command::Snapshot => 19,
+            Command::Ping => 20
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Data {
+    Str(String),
+    U64(u64),
+    I64(i64),
+    F64(f64),
+    Bool(bool),
+    Null,
+    Bytes(Vec<u8>)
+}
+
+impl<'de> Deserialize<'de> for Data {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+        where
+            D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Ok(Data::from_tagged(s.as_str()))
+    }
+}
+
+impl Serialize for Data {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+    {
+        serializer.serialize_str(self.to_tagged().as_str())
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Data::Str(s) => write!(f, "{}", s),
+            Data::U64(n) => write!(f, "{}", n),
+            Data::I64(n) => write!(f, "{}", n),
+            Data::F64(n) => write!(f, "{}", n),
+            Data::Bool(b) => write!(f, "{}", b),
+            Data::Null => write!(f, "null"),
+            Data::Bytes(bytes) => write!(f, "{}", to_hex(bytes))
+        }
+    }
+}
+
+impl From<&str> for Data {
+    fn from(s: &str) -> Data { Data::Str(s.to_owned()) }
+}
+
+impl From<String> for Data {
+    fn from(s: String) -> Data { Data::Str(s) }
+}
+
+impl From<u64> for Data {
+    fn from(n: u64) -> Data { Data::U64(n) }
+}
+
+impl From<usize> for Data {
+    fn from(n: usize) -> Data { Data::U64(n as u64) }
+}
+
+impl From<i64> for Data {
+    fn from(n: i64) -> Data { Data::I64(n) }
+}
+
+impl From<i32> for Data {
+    fn from(n: i32) -> Data { Data::I64(n as i64) }
+}
+
+impl From<f64> for Data {
+    fn from(n: f64) -> Data { Data::F64(n) }
+}
+
+impl From<bool> for Data {
+    fn from(b: bool) -> Data { Data::Bool(b) }
+}
+
+impl From<Vec<u8>> for Data {
+    fn from(bytes: Vec<u8>) -> Data { Data::Bytes(bytes) }
+}
+
+//objects and arrays have no single value counterpart and come out as null
+impl From<&serde_json::Value> for Data {
+    fn from(json: &serde_json::Value) -> Data {
+        match json {
+            serde_json::Value::String(s) => Data::Str(s.to_owned()),
+            serde_json::Value::Bool(b) => Data::Bool(b.to_owned()),
+            serde_json::Value::Number(n) => match (n.as_u64(), n.as_i64()) {
+                (Some(n), _) => Data::U64(n),
+                (None, Some(n)) => Data::I64(n),
+                _ => Data::F64(n.as_f64().unwrap())
+            },
+            _ => Data::Null
+        }
+    }
+}
+
+impl Data {
+    //binary form, a type tag byte followed by the value
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Data::Str(s) => [vec![0u8], write_string(s.to_owned())].concat(),
+            Data::U64(n) => [vec![1u8], write_usize(n.to_owned() as usize)].concat(),
+            Data::I64(n) => {
+                let mut wtr = vec![2u8];
+                wtr.write_i64::<LittleEndian>(n.to_owned()).unwrap();
+                wtr
+            },
+            Data::F64(n) => {
+                let mut wtr = vec![3u8];
+                wtr.write_f64::<LittleEndian>(n.to_owned()).unwrap();
+                wtr
+            },
+            Data::Bool(b) => vec![4u8, b.to_owned() as u8],
+            Data::Null => vec![5u8],
+            Data::Bytes(bytes) => [vec![6u8], bytes.to_owned()].concat()
+        }
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Data, DbError> {
+        if data.len() == 0 {
+            return Ok(Data::Null);
+        }
+
+        let payload = &data[1..];
+        match data[0] {
+            0 => Ok(Data::Str(read_string(payload))),
+            1 => Ok(Data::U64(read_usize(field(payload, 0, 8)?) as u64)),
+            2 => Ok(Data::I64(Cursor::new(field(payload, 0, 8)?).read_i64::<LittleEndian>().unwrap())),
+            3 => Ok(Data::F64(Cursor::new(field(payload, 0, 8)?).read_f64::<LittleEndian>().unwrap())),
+            4 => Ok(Data::Bool(field(payload, 0, 1)?[0] != 0)),
+            5 => Ok(Data::Null),
+            6 => Ok(Data::Bytes(payload.to_vec())),
+            //values written before types existed are plain text and never start with a tag byte
+            _ => Ok(Data::Str(read_string(data)))
+        }
+    }
+
+    //text form used by the CSV format and the reverse index, a type prefix followed by the value
+    pub fn to_tagged(&self) -> String {
+        match self {
+            Data::Str(s) => format!("s:{}", s),
+            Data::U64(n) => format!("u:{}", n),
+            Data::I64(n) => format!("i:{}", n),
+            Data::F64(n) => format!("f:{}", n),
+            Data::Bool(b) => format!("b:{}", b),
+            Data::Null => String::from("n:"),
+            Data::Bytes(bytes) => format!("x:{}", to_hex(bytes))
+        }
+    }
+
+    pub fn from_tagged(s: &str) -> Data {
+        if s.len() == 0 {
+            return Data::Null;
+        }
+
+        let (tag, val) = match s.find(':') {
+            Some(i) => (&s[..i], &s[(i + 1)..]),
+            None => ("", s)
+        };
+
+        let parsed = match tag {
+            "s" => Some(Data::Str(val.to_owned())),
+            "u" => val.parse::<u64>().ok().map(Data::U64),
+            "i" => val.parse::<i64>().ok().map(Data::I64),
+            "f" => val.parse::<f64>().ok().map(Data::F64),
+            "b" => val.parse::<bool>().ok().map(Data::Bool),
+            "n" => Some(Data::Null),
+            "x" => from_hex(val).map(Data::Bytes),
+            _ => None
+        };
+
+        //rows written before types existed hold plain text
+        match parsed {
+            Some(data) => data,
+            None => Data::Str(s.to_owned())
+        }
+    }
+
+    pub fn is_number(&self) -> bool {
+        match self {
+            Data::U64(_) | Data::I64(_) | Data::F64(_) => true,
+            _ => false
+        }
+    }
+
+    //the sum (or difference when sub is set) of two numbers. Floats stay floats, a whole number keeps its
+    //type unless an unsigned one goes below zero. None when either is not a number or the result does not fit
+    pub fn add(&self, by: &Data, sub: bool) -> Option<Data> {
+        match (self, by) {
+            (Data::F64(_), _) | (_, Data::F64(_)) => {
+                let (a, b) = (self.as_f64()?, by.as_f64()?);
+                Some(Data::F64(if sub { a - b } else { a + b }))
+            },
+            _ => {
+                let (a, b) = (self.as_i128()?, by.as_i128()?);
+                let n = if sub { a - b } else { a + b };
+                match self {
+                    Data::U64(_) if n >= 0 => u64::try_from(n).ok().map(Data::U64),
+                    _ => i64::try_from(n).ok().map(Data::I64)
+                }
+            }
+        }
+    }
+
+    fn as_i128(&self) -> Option<i128> {
+        match self {
+            Data::U64(n) => Some(*n as i128),
+            Data::I64(n) => Some(*n as i128),
+            _ => None
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Data::U64(n) => Some(*n as f64),
+            Data::I64(n) => Some(*n as f64),
+            Data::F64(n) => Some(*n),
+            _ => None
+        }
+    }
+
+    //bytes have no json counterpart so they are emitted as an array of numbers
+    pub fn to_json(&self) -> String {
+        match self {
+            Data::Str(s) => serde_json::to_string(s).unwrap(),
+            Data::F64(n) if !n.is_finite() => String::from("null"),
+            Data::Bytes(bytes) => serde_json::to_string(bytes).unwrap(),
+            _ => self.to_string()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Transaction {
+    pub cmd: Command,
+    pub obj: usize,
+    pub key: String,
+    pub val: Data,
+    pub othr: usize,
+    //the ids the transaction handed out when it was applied, in order. Set by the server when it logs the
+    //transaction so replaying it gives every node the id it had, None for requests and older database files
+    #[serde(default, serialize_with = "write_ids_column", deserialize_with = "read_ids_column")]
+    pub ids: Option<Vec<usize>>
+}
+
+//csv has no lists inside a column so the ids are written as a json array
+fn write_ids_column<S: Serializer>(ids: &Option<Vec<usize>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match ids {
+        Some(ids) => serializer.serialize_some(&serde_json::to_string(ids).unwrap()),
+        None => serializer.serialize_none()
+    }
+}
+
+fn read_ids_column<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Vec<usize>>, D::Error> {
+    match Option::<String>::deserialize(deserializer)? {
+        Some(s) => serde_json::from_str(s.as_str()).map(Some).map_err(serde::de::Error::custom),
+        None => Ok(None)
+    }
+}
+
+impl Transaction {
+    pub fn new (cmd: Command, obj: usize, key: String, val: Data, othr: usize) -> Transaction {
+        Transaction { cmd, obj, key, val, othr, ids: None }
+    }
+
+    //inside a batch an object id with this bit set stands for the id returned by an earlier transaction of
+    //the same batch, the rest of the bits being its position, so objects created by a batch can be used by it
+    pub const BATCH_REF: usize = 1 << 63;
+
+    pub fn UINT_SIZE() -> usize {
+        8//std::mem::size_of::<usize>()
+    }
+
+    pub fn from(data: Vec<u8>) -> Result<Transaction, DbError> {
+        let cmd = Command::from( field(&data, 0, 1)?[0] )?;
+
+        let usize_size = Transaction::UINT_SIZE();
+
+        //to_bytes always writes every field so they are all read back regardless of the command
+        let obj = read_usize( field(&data, 1, usize_size)? );
+
+        let key_size = read_usize( field(&data, usize_size + 1, usize_size)? );
+        let key = read_string( field(&data, usize_size*2 + 1, key_size)? );
+
+        let value_size = read_usize( field(&data, usize_size*2 + 1 + key_size, usize_size)? );
+        let value = Data::from_bytes( field(&data, usize_size*3 + 1 + key_size, value_size)? )?;
+
+        let other_node = read_usize( field(&data, usize_size*3 + 1 + key_size + value_size, usize_size)? );
+
+        let mut t = Self::new(cmd, obj, key, value, other_node);
+
+        //only logged transactions carry ids, after the other fields
+        let ids_start = usize_size*4 + 1 + key_size + value_size;
+        if data.len() > ids_start {
+            let ids_cnt = read_usize( field(&data, ids_start, usize_size)? );
+            let ids_bytes = field(&data, ids_start + usize_size, ids_cnt.checked_mul(usize_size).unwrap_or(usize::max_value()))?;
+            t.ids = Some(ids_bytes.chunks(usize_size).map(read_usize).collect());
+        }
+
+        Ok(t)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = vec![];
+
+        bytes.push(Command::to_u8(&self.cmd) );
+
+        let mut obj = write_usize(self.obj.to_owned());
+        bytes.append(&mut obj);
+
+        let mut key = write_string(self.key.to_owned());
+        let mut key_size = write_usize(key.len());
+        bytes.append(&mut key_size);
+        bytes.append(&mut key);
+
+        let mut value = self.val.to_bytes();
+        let mut value_size = write_usize(value.len());
+        bytes.append(&mut value_size);
+        bytes.append(&mut value);
+
+        let mut other_obj = write_usize(self.othr.to_owned());
+        bytes.append(&mut other_obj);
+
+        if let Some(ids) = &self.ids {
+            bytes.append(&mut write_usize(ids.len()));
+            for id in ids {
+                bytes.append(&mut write_usize(id.to_owned()));
+            }
+        }
+
+        bytes
+    }
+
+    //wraps transactions into one batch transaction, each of them framed with its size inside the value
+    pub fn batch(transactions: &[Transaction]) -> Transaction {
+        let mut bytes: Vec<u8> = vec![];
+        for t in transactions {
+            let t_bytes = t.to_bytes();
+            bytes.append(&mut write_usize(t_bytes.len()));
+            bytes.extend(t_bytes);
+        }
+
+        Transaction::new(Command::Batch, 0, String::new(), Data::Bytes(bytes), 0)
+    }
+
+    //sets the key only if it still holds the expected value, a missing key is expected as Null
+    pub fn compare_and_set(obj: usize, key: String, expected: Data, val: Data) -> Transaction {
+        let mut bytes: Vec<u8> = vec![];
+        for d in &[expected, val] {
+            let d_bytes = d.to_bytes();
+            bytes.append(&mut write_usize(d_bytes.len()));
+            bytes.extend(d_bytes);
+        }
+
+        Transaction::new(Command::CompareAndSet, obj, key, Data::Bytes(bytes), 0)
+    }
+
+    //sets the key only if it still points at the expected node, the value is sent as is
+    pub fn compare_and_set_node(obj: usize, key: String, expected: usize, val: Data) -> Transaction {
+        Transaction::new(Command::CompareAndSet, obj, key, val, expected)
+    }
+
+    //the expected value and the new value of a compare and set made with compare_and_set
+    pub fn compared(&self) -> Result<(Data, Data), DbError> {
+        let bytes = match &self.val {
+            Data::Bytes(b) => b,
+            _ => return Err(DbError::Malformed(String::from("a compare and set carries the expected and new value as bytes")))
+        };
+
+        let expected_size = read_usize( field(bytes, 0, Transaction::UINT_SIZE())? );
+        let expected = Data::from_bytes( field(bytes, Transaction::UINT_SIZE(), expected_size)? )?;
+
+        let val_start = Transaction::UINT_SIZE() + expected_size;
+        let val_size = read_usize( field(bytes, val_start, Transaction::UINT_SIZE())? );
+        let val = Data::from_bytes( field(bytes, val_start + Transaction::UINT_SIZE(), val_size)? )?;
+
+        Ok((expected, val))
+    }
+
+    //the transactions a batch transaction was made of
+    pub fn unbatch(&self) -> Result<Vec<Transaction>, DbError> {
+        let bytes = match &self.val {
+            Data::Bytes(b) => b,
+            _ => return Err(DbError::Malformed(String::from("a batch carries its transactions as bytes")))
+        };
+
+        let mut transactions = vec![];
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let t_size = read_usize( field(bytes, offset, Transaction::UINT_SIZE())? );
+            offset += Transaction::UINT_SIZE();
+
+            transactions.push( Transaction::from( field(bytes, offset, t_size)?.to_vec() )? );
+            offset += t_size;
+        }
+
+        Ok(transactions)
+    }
+
+    pub fn to_string(&self) -> String {
+        format!("{},{},\"{}\",\"{}\",{}\n", Command::to_u8(&self.cmd), self.obj, self.key, self.val.to_tagged(), self.othr)
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Status {
+    Ok,
+    Fail,
+    Error,
+    Conflict
+}
+
+impl Status {
+    pub fn from(n: u8) -> Result<Status, DbError> {
+        match n {
+            0 => Ok(Status::Ok),
+            1 => Ok(Status::Fail),
+            2 => Ok(Status::Error),
+            3 => Ok(Status::Conflict),
+            _=> Err(DbError::UnsupportedStatus(n))
+        }
+    }
+
+    pub fn to_u8(s: &Status) -> u8 {
+        match s {
+            Status::Ok => 0,
+            Status::Fail => 1,
+            Status::Error => 2,
+            Status::Conflict => 3
+        }
+    }
+}
+
+//what the server sends back for every transaction, framed as a size header followed by a status byte and the data
+#[derive(Debug)]
+pub struct Response {
+    pub status: Status,
+    pub data: Vec<u8>
+}
+
+impl Response {
+    pub fn new(status: Status, data: Vec<u8>) -> Response {
+        Response { status, data }
+    }
+
+    pub fn ok(data: Vec<u8>) -> Response {
+        Self::new(Status::Ok, data)
+    }
+
+    pub fn fail(data: Vec<u8>) -> Response {
+        Self::new(Status::Fail, data)
+    }
+
+    //the request was rejected, the data is the error message
+    pub fn error(e: DbError) -> Response {
+        Self::new(Status::Error, write_string(e.to_string()))
+    }
+
+    //a compare and set found something other than what was expected, the data is the id and value it found
+    pub fn conflict(data: Vec<u8>) -> Response {
+        Self::new(Status::Conflict, data)
+    }
+
+    //takes the bytes after the size header
+    pub fn from(data: Vec<u8>) -> Result<Response, DbError> {
+        let status = Status::from( field(&data, 0, 1)?[0] )?;
+        Ok(Self::new(status, data[1..].to_vec()))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = write_usize(self.data.len() + 1);
+        bytes.push(Status::to_u8(&self.status));
+        bytes.extend_from_slice(self.data.as_slice());
+
+        bytes
+    }
+}
+
+//the part of data starting at start that is len bytes long, or an error when data is too short
+pub fn field(data: &[u8], start: usize, len: usize) -> Result<&[u8], DbError> {
+    match start.checked_add(len) {
+        Some(end) if end <= data.len() => Ok(&data[start..end]),
+        _ => Err(DbError::Malformed(format!("expected {} bytes at offset {} of {}", len, start, data.len())))
+    }
+}
+
+pub fn write_usize(n: usize) -> Vec<u8> {
+    let mut wtr = vec![];
+    wtr.write_u64::<LittleEndian>(n as u64).unwrap();
+    wtr
+}
+
+pub fn write_string(s: String) -> Vec<u8> {
+    s.as_bytes().to_vec()
+}
+
+pub fn read_usize(data: &[u8]) -> usize {
+    let mut rdr = Cursor::new(data);
+    rdr.read_u64::<LittleEndian>().unwrap() as usize
+}
+
+pub fn read_string(data: &[u8]) -> String {
+    String::from_utf8_lossy(data).to_string()
+}
+
+pub fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+        return None;
+    }
+
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(&s[i..(i + 2)], 16).ok()).collect()
+}
\ No newline at end of file
@@ -1,4 +1,4 @@
-use crate::disk::DiskFormat;
+use crate::disk::{DiskFormat, Durability};
 use std::fs::{File, OpenOptions};
 use std::io::{BufReader, Write};
 use std::path::Path;
@@ -9,7 +9,20 @@ pub struct Config {
     pub file_name: String,
     pub file_format: DiskFormat,
     pub port: u32,
-    pub threads: usize
+    pub threads: usize,
+    #[serde(default)]
+    pub compact_size: u64,
+    #[serde(default)]
+    pub durability: Durability,
+    #[serde(default)]
+    pub snapshot_every: u64,
+    #[serde(default = "default_max_frame")]
+    pub max_frame: usize
+}
+
+//64MB, far above any single request while keeping a client from making the server allocate whatever it asks for
+fn default_max_frame() -> usize {
+    64 * 1024 * 1024
 }
 
 
@@ -22,7 +35,11 @@ impl Config {
                 file_name: "./db.bin".to_owned(),
                 file_format: DiskFormat::Bin,
                 port: 4000,
-                threads: 8
+                threads: 8,
+                compact_size: 0,
+                durability: Durability::Always,
+                snapshot_every: 0,
+                max_frame: default_max_frame()
             };
 
             match OpenOptions::new().create(true).write(true).open(path.to_owned()) {
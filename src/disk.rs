@@ -1,64 +1,252 @@
+use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
 use std::path::Path;
 use std::io::SeekFrom;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex, Condvar};
+use std::thread;
+use std::time::Duration;
 
-use crate::utils::{Transaction, read_usize, write_usize};
-use crate::Space;
+use crate::utils::{Transaction, Command, Data, read_usize, write_usize};
+use crate::space::{Space, Node};
 use csv::StringRecord;
 
-#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
 pub enum DiskFormat {
     Bin,
     CSV
 }
 
+//binary database files start with the magic bytes followed by the format version (u16), every record after that is
+//the size of the transaction (u64), the crc32 of the transaction bytes (u32) and then the transaction bytes.
+//files written before the header existed are version 0, records without a checksum
+const MAGIC: &[u8] = b"somedb";
+const VERSION: u16 = 1;
+const HEADER_SIZE: u64 = 8;
+const CRC_SIZE: u64 = 4;
+
+//when a write counts as done. None leaves it to the operating system, Always syncs the file before answering
+//and EveryMs syncs it in the background every so many milliseconds
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub enum Durability {
+    None,
+    Always,
+    EveryMs(u64)
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::Always
+    }
+}
+
+//writers wait for their transaction to reach the disk after letting go of the locks. Whoever finds no sync running
+//syncs everything written so far, so the writers that queued up in the meantime are covered by the same fsync
+pub struct GroupCommit {
+    durability: Durability,
+    state: Mutex<CommitState>,
+    synced_cond: Condvar
+}
+
+struct CommitState {
+    file: File,
+    written: u64,
+    synced: u64,
+    syncing: bool
+}
+
+impl GroupCommit {
+    //returns once the transaction numbered seq is as durable as configured
+    pub fn wait(&self, seq: u64) {
+        if let Durability::Always = self.durability {
+            self.sync(seq);
+        }
+    }
+
+    fn sync(&self, seq: u64) {
+        let mut state = self.state.lock().unwrap();
+        while state.synced < seq {
+            if state.syncing {
+                state = self.synced_cond.wait(state).unwrap();
+                continue;
+            }
+
+            state.syncing = true;
+            let target = state.written;
+            let file = state.file.try_clone().unwrap();
+            drop(state);
+
+            let synced = file.sync_data();
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            self.synced_cond.notify_all();
+            match synced {
+                Ok(_) => if target > state.synced { state.synced = target },
+                Err(why) => panic!("couldn't sync database file: {}", why)
+            };
+        }
+    }
+
+    fn sync_written(&self) {
+        let written = self.state.lock().unwrap().written;
+        self.sync(written);
+    }
+}
+
 pub struct Disk {
     path: String,
-    format: DiskFormat
+    format: DiskFormat,
+    compact_size: u64,
+    compacted_size: u64,
+    snapshot_every: u64,
+    //the number of the last transaction covered by the snapshot
+    snapshot_written: u64,
+    //kept open for appending, replaced along with the file on compaction
+    file: File,
+    commit: Arc<GroupCommit>
 }
 
 pub struct DiskIterator {
+    //whether a corrupt tail is cut off the file or only skipped
+    repair: bool,
+    //why the iterator stopped before the end of the file when it was not a torn record
+    corrupt: Option<String>,
     offset: u64,
+    len: u64,
+    version: u16,
+    path: String,
     file: File,
-    csv_reader: csv::Reader<File>,
+    //only csv files are read through it
+    csv_reader: Option<csv::Reader<File>>,
     format: DiskFormat
 }
 
 impl DiskIterator {
     fn binary_iterate(&mut self) -> Option<Transaction> {
-        self.file.seek(SeekFrom::Start(self.offset)).unwrap();
+        if self.offset >= self.len {
+            return None;
+        }
 
-        let mut t_size_bytes = vec![0u8; Transaction::UINT_SIZE()];
-        match self.file.read_exact(&mut t_size_bytes) {
-            Ok(_) => {},
-            Err(e) => return None
+        match self.read_record() {
+            Ok(t) => Some(t),
+            Err((why, torn)) => self.bad_record(why, torn)
+        }
+    }
+
+    //a record that cannot be read and runs to the end of the file is what a crash in the middle of a write leaves
+    //behind, so it is cut off. One with more records after it means the file was damaged some other way and
+    //cutting it off would throw those away too
+    fn bad_record(&mut self, why: String, torn: bool) -> Option<Transaction> {
+        match (self.repair, torn) {
+            (true, true) => self.truncate(why),
+            (true, false) => {
+                self.corrupt = Some(format!("database file {} is corrupt at offset {} ({}) with more records after it, refusing to load it. \
+                    Cut the file at that offset by hand to load the records before it", self.path, self.offset, why));
+            },
+            (false, _) => {
+                self.corrupt = Some(format!("database file {} is corrupt at offset {} ({})", self.path, self.offset, why));
+            }
         };
+        None
+    }
+
+    //fails with why the record could not be read and whether it runs to the end of the file
+    fn read_record(&mut self) -> Result<Transaction, (String, bool)> {
+        self.file.seek(SeekFrom::Start(self.offset)).map_err(|e| (format!("{}", e), false))?;
+
+        let mut t_size_bytes = vec![0u8; Transaction::UINT_SIZE()];
+        self.file.read_exact(&mut t_size_bytes).map_err(|e| (format!("incomplete record size: {}", e), true))?;
         let t_size = read_usize(t_size_bytes.as_slice());
 
-        if t_size == 0 {
-            return None;
+        let mut crc_bytes = [0u8; CRC_SIZE as usize];
+        let header_size = if self.version > 0 {
+            self.file.read_exact(&mut crc_bytes).map_err(|e| (format!("incomplete record checksum: {}", e), true))?;
+            Transaction::UINT_SIZE() as u64 + CRC_SIZE
+        }else {
+            Transaction::UINT_SIZE() as u64
+        };
+
+        //checked before reading so a garbage size does not turn into a huge allocation
+        if t_size == 0 || t_size as u64 > self.len - self.offset - header_size {
+            return Err((format!("record size {} does not fit the file", t_size), true));
         }
 
-        self.file.seek(SeekFrom::Start(self.offset + Transaction::UINT_SIZE() as u64 )).unwrap();//skip the size bytes
         let mut t_bytes = vec![0u8; t_size];
-        self.file.read_exact(&mut t_bytes);
+        self.file.read_exact(&mut t_bytes).map_err(|e| (format!("incomplete record: {}", e), true))?;
+
+        let last = self.offset + header_size + t_size as u64 == self.len;
+        if self.version > 0 && crc32fast::hash(&t_bytes) != u32::from_le_bytes(crc_bytes) {
+            return Err((String::from("checksum mismatch"), last));
+        }
 
-        self.offset += (t_size + Transaction::UINT_SIZE()) as u64;
-        let t = Transaction::from(t_bytes.to_vec());
+        let t = Transaction::from(t_bytes).map_err(|e| (format!("{}", e), last))?;
+        self.offset += header_size + t_size as u64;
 
-        Some(t)
+        Ok(t)
+    }
+
+    //cuts a torn record off the file. The cut off bytes are kept next to the file in case they are worth looking
+    //into, without replacing what an earlier repair kept
+    fn truncate(&mut self, why: String) {
+        let mut corrupt_path = format!("{}.corrupt", self.path);
+        let mut n = 1;
+        while Path::new(corrupt_path.as_str()).exists() {
+            corrupt_path = format!("{}.corrupt.{}", self.path, n);
+            n += 1;
+        }
+
+        warn!("database file is corrupt at offset {} ({}), moving the last {} bytes to {} and truncating",
+              self.offset, why, self.len - self.offset, corrupt_path);
+
+        let mut file = match OpenOptions::new().read(true).write(true).open(self.path.to_owned()) {
+            Ok(file) => file,
+            Err(why) => panic!("couldn't truncate database file: {}", why)
+        };
+
+        let mut rest = vec![];
+        file.seek(SeekFrom::Start(self.offset)).unwrap();
+        match file.read_to_end(&mut rest) {
+            Ok(_) => { fs::write(corrupt_path, rest).unwrap(); },
+            Err(e) => error!("couldn't copy the corrupt bytes: {}", e)
+        };
+
+        file.set_len(self.offset).unwrap();
+        self.len = self.offset;
     }
 
     fn csv_iterate(&mut self) -> Option<Transaction> {
-        match self.csv_reader.records().next() {
-            Some(t) => Some(t.unwrap().deserialize(None).unwrap()),
-            None => None
+        let reader = self.csv_reader.as_mut()?;
+
+        //positions are counted from where the reader started
+        let row_offset = self.offset + reader.position().byte();
+        let row = match reader.records().next() {
+            Some(Ok(record)) => record.deserialize(None).map_err(|e| format!("{}", e)),
+            Some(Err(e)) => Err(format!("{}", e)),
+            None => return None
+        };
+
+        match row {
+            Ok(t) => Some(t),
+            Err(why) => {
+                //a crash can only tear the last row
+                let torn = reader.records().next().is_none();
+                self.offset = row_offset;
+                self.bad_record(why, torn)
+            }
         }
     }
 }
 
+impl DiskIterator {
+    //set once the iterator has stopped at a damaged record that was not cut off, see bad_record
+    pub fn corrupt(&self) -> Option<&String> {
+        self.corrupt.as_ref()
+    }
+}
+
 impl Iterator for DiskIterator {
     type Item = Transaction;
 
@@ -71,68 +259,707 @@ impl Iterator for DiskIterator {
 }
 
 impl Disk {
-    pub fn new(path: &str, format: DiskFormat) -> Disk {
-        if !Path::new(path).exists() {
-            File::create(path.to_owned()).unwrap();
+    pub fn new(path: &str, format: DiskFormat, compact_size: u64, durability: Durability, snapshot_every: u64) -> Disk {
+        match Disk::open(path, format, compact_size, durability, snapshot_every) {
+            Ok(disk) => disk,
+            Err(why) => panic!("{}", why)
         }
+    }
 
-        if !Path::new("./null").exists() {
-            File::create(String::from("./null")).unwrap();
+    //same as new but a file that cannot be used is an error instead of a panic
+    pub fn open(path: &str, format: DiskFormat, compact_size: u64, durability: Durability, snapshot_every: u64) -> Result<Disk, String> {
+        if !Path::new(path).exists() {
+            File::create(path.to_owned()).map_err(|e| format!("couldn't create database file {}: {}", path, e))?;
         }
         
-        Disk { path: path.to_string(), format }
+        match Disk::format_of(path) {
+            Some(found) if found != format => return Err(format!("database file {} is written in the {:?} format but file_format in config.json is {:?}. \
+                Either set file_format back to {:?} or convert the file with: somedb convert {} {:?} <new file> {:?}",
+                path, found, format, found, path, found, format)),
+            _ => {}
+        };
+
+        if let DiskFormat::Bin = format {
+            match Disk::version_of(path) {
+                Some(VERSION) => {},
+                Some(0) => Disk::upgrade(path, format)?,
+                Some(v) => return Err(format!("database file is format version {}, this build reads up to version {}", v, VERSION)),
+                None => Disk::write_header(&mut Disk::open_append(path))
+            };
+        }
+
+        let file = Disk::open_append(path);
+        let commit = Arc::new(GroupCommit {
+            durability,
+            state: Mutex::new(CommitState { file: file.try_clone().unwrap(), written: 0, synced: 0, syncing: false }),
+            synced_cond: Condvar::new()
+        });
+
+        //the thread only holds on to the commit between syncs, it ends once the disk is dropped
+        if let Durability::EveryMs(ms) = durability {
+            let background = Arc::downgrade(&commit);
+            thread::spawn(move || {
+                loop {
+                    thread::sleep(Duration::from_millis(ms));
+                    match background.upgrade() {
+                        Some(commit) => commit.sync_written(),
+                        None => break
+                    };
+                }
+            });
+        }
+
+        Ok(Disk { path: path.to_string(), format, compact_size, compacted_size: 0, snapshot_every, snapshot_written: 0, file, commit })
     }
 
-    pub fn log_transaction(&self, t: &Transaction) {
-        let mut file = match OpenOptions::new().append(true).open(self.path.to_owned()) {
+    //the format a database file was written in, None when the file is still empty. Binary files start with the magic
+    //bytes, except those from before the header which are told apart by not starting with a csv transaction
+    fn format_of(path: &str) -> Option<DiskFormat> {
+        match Disk::version_of(path) {
+            None => None,
+            Some(v) if v > 0 => Some(DiskFormat::Bin),
+            Some(_) => {
+                let file = match File::open(path) {
+                    Err(why) => panic!("couldn't open database file: {}", why),
+                    Ok(file) => file,
+                };
+
+                let mut reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).double_quote(true).from_reader(file);
+                match reader.records().next() {
+                    Some(Ok(record)) if record.deserialize::<Transaction>(None).is_ok() => Some(DiskFormat::CSV),
+                    _ => Some(DiskFormat::Bin)
+                }
+            }
+        }
+    }
+
+    //writes every transaction of a database file to a new file in the other format, returns how many there were.
+    //the file being read is left as it is, one with a damaged record fails the conversion without replacing the new
+    //file. A torn record from a crash is cut off by opening the file with somedb first
+    pub fn convert(from_path: &str, from_format: DiskFormat, to_path: &str, to_format: DiskFormat) -> Result<usize, String> {
+        match Disk::format_of(from_path) {
+            Some(found) if found != from_format => return Err(format!("{} is written in the {:?} format, not {:?}", from_path, found, from_format)),
+            _ => {}
+        };
+
+        let tmp_path = format!("{}.convert", to_path);
+        let file = File::create(tmp_path.to_owned()).map_err(|e| format!("couldn't create {}: {}", tmp_path, e))?;
+        let mut writer = std::io::BufWriter::new(file);
+
+        if let DiskFormat::Bin = to_format {
+            Disk::write_header(&mut writer);
+        }
+
+        let mut transactions = Disk::iterate(from_path, from_format, 0);
+        transactions.repair = false;
+
+        let mut cnt = 0;
+        let mut failed = None;
+        for t in transactions.by_ref() {
+            if let Err(why) = Disk::write_transactions(to_format, &mut writer, std::slice::from_ref(&t)) {
+                failed = Some(format!("couldn't write {}: {}", tmp_path, why));
+                break;
+            }
+            cnt += 1;
+        }
+
+        if failed.is_none() {
+            failed = transactions.corrupt().map(|why| format!("{}, converted {} transactions before it", why, cnt));
+        }
+        if failed.is_none() {
+            failed = match writer.into_inner().map(|file| file.sync_all()) {
+                Ok(Ok(_)) => None,
+                _ => Some(format!("couldn't write {}", tmp_path))
+            };
+        }
+        if let Some(why) = failed {
+            let _ = fs::remove_file(tmp_path);
+            return Err(why);
+        }
+
+        //a snapshot left from an older file of the same name would point into the wrong file
+        let snapshot_path = format!("{}.snapshot", to_path);
+        if Path::new(snapshot_path.as_str()).exists() {
+            fs::remove_file(snapshot_path.to_owned()).map_err(|e| format!("couldn't remove {}: {}", snapshot_path, e))?;
+        }
+
+        fs::rename(tmp_path, to_path).map_err(|e| format!("couldn't replace {}: {}", to_path, e))?;
+
+        Ok(cnt)
+    }
+
+    //the format version of the binary database file, None when the file is still empty
+    fn version_of(path: &str) -> Option<u16> {
+        let mut file = match File::open(path) {
             Err(why) => panic!("couldn't open database file: {}", why),
             Ok(file) => file,
         };
 
-        match self.format {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        match file.read_exact(&mut header) {
+            Ok(_) if &header[..MAGIC.len()] == MAGIC => Some(u16::from_le_bytes([header[MAGIC.len()], header[MAGIC.len() + 1]])),
+            _ if file.metadata().map(|m| m.len()).unwrap_or(0) == 0 => None,
+            _ => Some(0)
+        }
+    }
+
+    fn write_header<W: Write>(file: &mut W) {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        match file.write_all(header.as_slice()) {
+            Err(why) => panic!("couldn't write database file header: {}", why),
+            Ok(_) => {}
+        };
+    }
+
+    //rewrites a database file from before the header existed in the current format
+    fn upgrade(path: &str, format: DiskFormat) -> Result<(), String> {
+        info!("upgrading database file to format version {}", VERSION);
+        let mut iter = Disk::iterate(path, format, 0);
+        let transactions: Vec<Transaction> = iter.by_ref().collect();
+        if let Some(why) = iter.corrupt() {
+            return Err(why.to_owned());
+        }
+
+        Disk::replace(path, format, transactions.as_slice());
+        Ok(())
+    }
+
+    fn open_append(path: &str) -> File {
+        match OpenOptions::new().append(true).open(path) {
+            Err(why) => panic!("couldn't open database file: {}", why),
+            Ok(file) => file,
+        }
+    }
+
+    //writes the transactions to a new file that is swapped in only once it is complete
+    fn replace(path: &str, format: DiskFormat, transactions: &[Transaction]) {
+        let tmp_path = format!("{}.compact", path);
+        let mut file = match File::create(tmp_path.to_owned()) {
+            Err(why) => panic!("couldn't create new database file: {}", why),
+            Ok(file) => file,
+        };
+
+        if let DiskFormat::Bin = format {
+            Disk::write_header(&mut file);
+        }
+        if let Err(why) = Disk::write_transactions(format, &mut file, transactions) {
+            panic!("couldn't write new database file: {}", why);
+        }
+        match file.sync_all() {
+            Err(why) => panic!("couldn't write new database file: {}", why),
+            Ok(_) => {}
+        };
+
+        match fs::rename(tmp_path, path) {
+            Err(why) => panic!("couldn't replace database file: {}", why),
+            Ok(_) => {}
+        };
+    }
+
+    fn write_transactions<W: Write>(format: DiskFormat, mut file: W, transactions: &[Transaction]) -> Result<(), String> {
+        match format {
             DiskFormat::Bin => {
-                let t_bytes: Vec<u8> = t.to_bytes();
-                let t_size: Vec<u8> = write_usize(t_bytes.len().to_owned());
-                let bytes: Vec<u8> = [t_size, t_bytes].concat();
-                file.write_all(bytes.as_slice() );
+                let mut bytes: Vec<u8> = vec![];
+                for t in transactions {
+                    let t_bytes: Vec<u8> = t.to_bytes();
+                    let t_size: Vec<u8> = write_usize(t_bytes.len().to_owned());
+                    bytes.extend(t_size);
+                    bytes.extend_from_slice(&crc32fast::hash(&t_bytes).to_le_bytes());
+                    bytes.extend(t_bytes);
+                }
+                file.write_all(bytes.as_slice()).map_err(|e| format!("{}", e))
             },
             DiskFormat::CSV => {
                 let mut wtr = csv::WriterBuilder::new()
                     .has_headers(false).double_quote(true).from_writer(file);
-                wtr.serialize(t);
-                wtr.flush();
+                for t in transactions {
+                    wtr.serialize(t).map_err(|e| format!("{}", e))?;
+                }
+                wtr.flush().map_err(|e| format!("{}", e))
             }
-        };
+        }
+    }
+
+    //appends the transaction to the file and returns its number, pass it to GroupCommit::wait to wait for it to be durable.
+    //a write that fails part way is cut off again so the next transaction does not land behind a torn record
+    pub fn log_transaction(&self, t: &Transaction) -> Result<u64, String> {
+        let len = self.size();
+        if let Err(why) = Disk::write_transactions(self.format, &self.file, std::slice::from_ref(t)) {
+            match self.file.set_len(len) {
+                Err(e) => error!("couldn't cut off the failed write: {}", e),
+                Ok(_) => {}
+            };
+            return Err(why);
+        }
+
+        let mut state = self.commit.state.lock().unwrap();
+        state.written += 1;
+        Ok(state.written)
+    }
+
+    pub fn group_commit(&self) -> Arc<GroupCommit> {
+        Arc::clone(&self.commit)
+    }
+
+    pub fn size(&self) -> u64 {
+        match fs::metadata(self.path.to_owned()) {
+            Ok(meta) => meta.len(),
+            Err(why) => panic!("couldn't read database file: {}", why)
+        }
+    }
+
+    //compaction kicks in once the file passes the configured size and has at least doubled since it was last compacted,
+    //so a space that is simply big does not get rewritten on every write
+    pub fn needs_compaction(&self) -> bool {
+        let size = self.size();
+        self.compact_size > 0 && size > self.compact_size && size > self.compacted_size * 2
     }
 
     pub fn load_transactions(&self) -> DiskIterator {
-        match self.format {
+        Disk::iterate(self.path.as_str(), self.format, 0)
+    }
+
+    //the transactions written after the given offset of the file, used to replay what came after a snapshot
+    pub fn load_transactions_from(&self, offset: u64) -> DiskIterator {
+        Disk::iterate(self.path.as_str(), self.format, offset)
+    }
+
+    fn iterate(path: &str, format: DiskFormat, offset: u64) -> DiskIterator {
+        let len = match fs::metadata(path) {
+            Ok(meta) => meta.len(),
+            Err(why) => panic!("couldn't read database file: {}", why)
+        };
+
+        match format {
             DiskFormat::Bin => {
+                let version = Disk::version_of(path).unwrap_or(VERSION);
                 DiskIterator {
-                    offset: 0,
-                    format: self.format.to_owned(),
-                    csv_reader: csv::Reader::from_reader(File::open("./null").unwrap()),
-                    file: match OpenOptions::new().read(true).open(self.path.to_owned()) {
+                    repair: true,
+                    corrupt: None,
+                    offset: if version > 0 { offset.max(HEADER_SIZE) } else { offset },
+                    len,
+                    version,
+                    path: path.to_owned(),
+                    format,
+                    csv_reader: None,
+                    file: match OpenOptions::new().read(true).open(path) {
                         Err(why) => panic!("couldn't open database file: {}", why),
                         Ok(file) => file,
                     }
                 }
             },
             DiskFormat::CSV => {
+                let mut file = match OpenOptions::new().read(true).open(path) {
+                    Err(why) => panic!("couldn't open database file: {}", why),
+                    Ok(file) => file,
+                };
+                file.seek(SeekFrom::Start(offset)).unwrap();
+                let reader = csv::ReaderBuilder::new().has_headers(false).flexible(true).double_quote(true).from_reader(file);
+
                 DiskIterator {
-                    offset: 0,
-                    format: self.format.to_owned(),
-                    csv_reader: csv::ReaderBuilder::new().has_headers(false).double_quote(true).from_reader(match OpenOptions::new().read(true).open(self.path.to_owned()) {
+                    repair: true,
+                    corrupt: None,
+                    offset,
+                    len,
+                    version: 0,
+                    path: path.to_owned(),
+                    format,
+                    file: match OpenOptions::new().read(true).open(path) {
                         Err(why) => panic!("couldn't open database file: {}", why),
                         Ok(file) => file,
-                    }),
-                    file: File::open("./null").unwrap()
+                    },
+                    csv_reader: Some(reader)
                 }
             }
         }
     }
 
-    pub fn clean(&self, space: &Space) {
-        println!("not implemented yet");
+    //rewrites the database file as the smallest set of transactions that rebuilds the space, keeping every id as it is.
+    //nothing is collected, every node is written and replaying the links restores which objects are roots
+    pub fn clean(&mut self, space: &Space) {
+        let mut ids: Vec<usize> = space.nodes.keys().cloned().collect();
+        ids.sort();
+
+        //every node that gets made is logged with the id it has, replaying puts it back at that id
+        let made = |cmd: Command, obj: usize, key: &str, val: Data, id: usize| {
+            let mut t = Transaction::new(cmd, obj, key.to_owned(), val, 0);
+            t.ids = Some(vec![id]);
+            t
+        };
+
+        let mut transactions: Vec<Transaction> = vec![];
+        for id in &ids {
+            match space.nodes.get(id) {
+                Some(Node::Entity(_)) => transactions.push(made(Command::Create, 0, "", Data::Null, id.to_owned())),
+                Some(Node::List(_)) => transactions.push(made(Command::CreateList, 0, "", Data::Null, id.to_owned())),
+                _ => {}
+            }
+        }
+
+        //nodes nothing links to that gc has not reclaimed: values, and objects and lists that were detached
+        //without being roots. A client may still hold their ids so they are kept as they are
+        let linked: HashSet<usize> = space.nodes.values().flat_map(|node| match node {
+            Node::Entity(ent) => ent.props.values().cloned().collect(),
+            Node::List(list) => list.items.clone(),
+            Node::Value(_) => vec![]
+        }).collect();
+        let loose: Vec<usize> = ids.iter().filter(|id| !linked.contains(id) && !space.roots.contains(id)).cloned().collect();
+
+        //ids handed out to nodes that are gone must not be handed out again after a restart, so the last one is
+        //made and dropped again. The same object holds loose nodes for a moment, values can only be made under
+        //a key and detached objects stop being roots only once linked. When it is not needed for the id any
+        //object will do, nothing links anywhere yet so putting a node under it cannot make a cycle
+        let last_id = space.last_id();
+        let first_entity = ids.iter().find(|id| match space.nodes.get(id) {
+            Some(Node::Entity(_)) => true,
+            _ => false
+        }).cloned();
+        let placeholder = match (space.nodes.contains_key(&last_id), first_entity) {
+            (false, _) if last_id > 0 => Some(last_id),
+            (_, None) if !loose.is_empty() => Some(last_id + 1),
+            _ => None
+        };
+        if let Some(id) = placeholder {
+            transactions.push(made(Command::Create, 0, "", Data::Null, id));
+        }
+
+        //a value is made the first time it is reached and linked after that. Values are shared by what they hold
+        //so two nodes holding the same value come back as one, the first
+        let mut written_values: HashMap<String, usize> = HashMap::new();
+        if let Some(holder) = placeholder.or(first_entity) {
+            for id in &loose {
+                match space.nodes.get(id) {
+                    Some(Node::Value(v)) => match written_values.get(&v.val.to_tagged()) {
+                        Some(_) => continue,
+                        None => {
+                            written_values.insert(v.val.to_tagged(), id.to_owned());
+                            transactions.push(made(Command::Set, holder, "loose", v.val.to_owned(), id.to_owned()));
+                        }
+                    },
+                    _ => transactions.push(Transaction::new(Command::Link, holder, String::from("loose"), Data::Null, id.to_owned()))
+                };
+                transactions.push(Transaction::new(Command::Unlink, holder, String::from("loose"), Data::Null, 0));
+            }
+        }
+
+        for id in &ids {
+            match space.nodes.get(id) {
+                Some(Node::Entity(ent)) => {
+                    let mut keys: Vec<&String> = ent.props.keys().collect();
+                    keys.sort();
+
+                    for key in keys {
+                        let prop = ent.props[key];
+                        let t = match space.nodes.get(&prop) {
+                            Some(Node::Value(v)) => match written_values.get(&v.val.to_tagged()) {
+                                Some(first) => Transaction::new(Command::Link, id.to_owned(), key.to_owned(), Data::Null, first.to_owned()),
+                                None => {
+                                    written_values.insert(v.val.to_tagged(), prop);
+                                    made(Command::Set, id.to_owned(), key, v.val.to_owned(), prop)
+                                }
+                            },
+                            _ => Transaction::new(Command::Link, id.to_owned(), key.to_owned(), Data::Null, prop)
+                        };
+                        transactions.push(t);
+                    }
+                },
+                Some(Node::List(list)) => {
+                    //items pointing at nodes that are gone come back as null, which is how they read anyway
+                    for item in &list.items {
+                        let val = match space.nodes.get(item) {
+                            Some(Node::Value(v)) => Some(v.val.to_owned()),
+                            Some(_) => None,
+                            None => Some(Data::Null)
+                        };
+
+                        let t = match val {
+                            Some(val) => match written_values.get(&val.to_tagged()) {
+                                Some(first) => Transaction::new(Command::Append, id.to_owned(), String::new(), Data::Null, first.to_owned()),
+                                None => {
+                                    written_values.insert(val.to_tagged(), item.to_owned());
+                                    made(Command::Append, id.to_owned(), "", val, item.to_owned())
+                                }
+                            },
+                            None => Transaction::new(Command::Append, id.to_owned(), String::new(), Data::Null, item.to_owned())
+                        };
+                        transactions.push(t);
+                    }
+                },
+                _ => {}
+            }
+        }
+
+        if let Some(id) = placeholder {
+            transactions.push(Transaction::new(Command::Delete, id, String::new(), Data::Null, 0));
+        }
+
+        //offsets into the old file mean nothing in the new one, the snapshot goes first so a crash in between
+        //cannot leave a snapshot pointing into the wrong file
+        let snapshot_path = self.snapshot_path();
+        if Path::new(snapshot_path.as_str()).exists() {
+            match fs::remove_file(snapshot_path) {
+                Err(why) => panic!("couldn't remove snapshot: {}", why),
+                Ok(_) => {}
+            };
+        }
+
+        Disk::replace(self.path.as_str(), self.format, transactions.as_slice());
+
+        //the new file was synced before it was swapped in, so everything written so far is durable
+        self.file = Disk::open_append(self.path.as_str());
+        let mut state = self.commit.state.lock().unwrap();
+        state.file = self.file.try_clone().unwrap();
+        state.synced = state.written;
+        drop(state);
+
+        self.compacted_size = self.size();
+        info!("compacted database file to {} transactions ({} bytes)", transactions.len(), self.compacted_size);
+
+        if self.snapshot_every > 0 {
+            self.snapshot(space);
+        }
+    }
+
+    fn snapshot_path(&self) -> String {
+        format!("{}.snapshot", self.path)
     }
-}
\ No newline at end of file
+
+    pub fn needs_snapshot(&self) -> bool {
+        self.snapshot_every > 0 && self.commit.state.lock().unwrap().written - self.snapshot_written >= self.snapshot_every
+    }
+
+    //writes the whole space along with the current size of the database file, so startup only has to replay
+    //what was written after it. Like compaction it is written next to the old one and swapped in when complete
+    pub fn snapshot(&mut self, space: &Space) {
+        let tmp_path = format!("{}.tmp", self.snapshot_path());
+        let file = match File::create(tmp_path.to_owned()) {
+            Err(why) => panic!("couldn't create snapshot: {}", why),
+            Ok(file) => file,
+        };
+
+        //the snapshot is synced below, the part of the database file it covers has to be on disk before it or a
+        //crash could leave a snapshot pointing past what the file holds
+        self.commit.sync_written();
+        let log_offset = self.size();
+        let mut writer = std::io::BufWriter::new(file);
+        if let Err(why) = space.write_snapshot(&mut writer, log_offset) {
+            panic!("couldn't write snapshot: {}", why);
+        }
+
+        match writer.into_inner().map(|file| file.sync_all()) {
+            Ok(Ok(_)) => {},
+            _ => panic!("couldn't write snapshot")
+        };
+
+        match fs::rename(tmp_path, self.snapshot_path()) {
+            Err(why) => panic!("couldn't replace snapshot: {}", why),
+            Ok(_) => {}
+        };
+
+        self.snapshot_written = self.commit.state.lock().unwrap().written;
+        info!("wrote snapshot of {} nodes covering {} bytes of the database file", space.nodes.len(), log_offset);
+    }
+
+    //the space held by the latest snapshot and the offset of the database file to replay from, None when there is
+    //no usable snapshot and the whole file has to be replayed
+    pub fn load_snapshot(&self) -> Option<(Space, u64)> {
+        let file = match File::open(self.snapshot_path()) {
+            Ok(file) => file,
+            Err(_) => return None
+        };
+
+        let why = match Space::read_snapshot(std::io::BufReader::new(file)) {
+            Ok((_, log_offset)) if log_offset > self.size() => String::from("it covers more than the database file holds"),
+            Ok(loaded) => return Some(loaded),
+            Err(why) => format!("it cannot be read: {}", why)
+        };
+
+        //left in place it would be picked up again by the next snapshot taken after the file grows past it
+        warn!("removing snapshot, {}", why);
+        if let Err(e) = fs::remove_file(self.snapshot_path()) {
+            error!("couldn't remove snapshot: {}", e);
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::space::Space;
+    use crate::engine::Engine;
+
+    #[test]
+    fn log_format() {
+        let path = std::env::temp_dir().join("somedb_log_format.bin");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        //a file from before the header existed is upgraded when opened
+        let legacy = Transaction::new(Command::Create, 0, String::new(), Data::Null, 0).to_bytes();
+        fs::write(path, [write_usize(legacy.len()), legacy].concat()).unwrap();
+
+        let disk = Disk::new(path, DiskFormat::Bin, 0, Durability::Always, 0);
+        assert!(fs::read(path).unwrap().starts_with(b"somedb"));
+        disk.log_transaction(&Transaction::new(Command::Set, 1, "age".to_owned(), Data::U64(55), 0)).unwrap();
+        assert_eq!(disk.load_transactions().count(), 2);
+
+        //a record cut short is dropped along with everything after it
+        let size = disk.size();
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&write_usize(100)).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        assert_eq!(disk.load_transactions().count(), 2);
+        assert_eq!(disk.size(), size);
+
+        //so is a last record that does not match its checksum, the bytes cut off earlier are kept
+        let mut bytes = fs::read(path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+        fs::write(path, bytes).unwrap();
+        assert_eq!(disk.load_transactions().count(), 1);
+        assert!(Path::new(format!("{}.corrupt", path).as_str()).exists());
+        assert!(Path::new(format!("{}.corrupt.1", path).as_str()).exists());
+
+        //a bad record with more after it is not a torn write, the file is left alone
+        let damaged = disk.size();
+        disk.log_transaction(&Transaction::new(Command::Set, 1, "age".to_owned(), Data::U64(55), 0)).unwrap();
+        disk.log_transaction(&Transaction::new(Command::Set, 1, "age".to_owned(), Data::U64(56), 0)).unwrap();
+        let mut bytes = fs::read(path).unwrap();
+        bytes[damaged as usize + 12] ^= 0xff;
+        fs::write(path, bytes).unwrap();
+        let size = disk.size();
+        let mut transactions = disk.load_transactions();
+        assert_eq!(transactions.by_ref().count(), 1);
+        assert!(transactions.corrupt().is_some());
+        assert_eq!(disk.size(), size);
+        assert!(Engine::load(Disk::new(path, DiskFormat::Bin, 0, Durability::Always, 0)).is_err());
+
+        let _ = fs::remove_file(path);
+        let _ = fs::remove_file(format!("{}.corrupt", path));
+        let _ = fs::remove_file(format!("{}.corrupt.1", path));
+    }
+
+    #[test]
+    fn group_commit() {
+        let path = std::env::temp_dir().join("somedb_group_commit.bin");
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(path.as_str());
+
+        let disk = std::sync::Arc::new(Disk::new(path.as_str(), DiskFormat::Bin, 0, Durability::Always, 0));
+        let writers: Vec<_> = (0..8).map(|i| {
+            let disk = std::sync::Arc::clone(&disk);
+            std::thread::spawn(move || {
+                for j in 0..10 {
+                    let seq = disk.log_transaction(&Transaction::new(Command::Set, 1, format!("{}_{}", i, j), Data::U64(j), 0)).unwrap();
+                    disk.group_commit().wait(seq);
+                }
+            })
+        }).collect();
+        for w in writers {
+            w.join().unwrap();
+        }
+
+        assert_eq!(disk.load_transactions().count(), 80);
+
+        //the background sync of a dropped disk stops holding on to its file
+        let disk = Disk::new(path.as_str(), DiskFormat::Bin, 0, Durability::EveryMs(1), 0);
+        let commit = std::sync::Arc::downgrade(&disk.group_commit());
+        drop(disk);
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        assert!(commit.upgrade().is_none());
+        let _ = fs::remove_file(path.as_str());
+    }
+
+    #[test]
+    fn snapshot() {
+        let path = std::env::temp_dir().join("somedb_snapshot.bin");
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(path.as_str());
+        let _ = fs::remove_file(format!("{}.snapshot", path));
+
+        let mut space = Space::new();
+        let obj = space.create();
+        space.set(obj, "name", &Data::from("tim")).unwrap();
+
+        let mut disk = Disk::new(path.as_str(), DiskFormat::Bin, 0, Durability::None, 0);
+        disk.log_transaction(&Transaction::new(Command::Create, 0, String::new(), Data::Null, 0)).unwrap();
+        disk.snapshot(&space);
+        disk.log_transaction(&Transaction::new(Command::Set, obj, "age".to_owned(), Data::U64(55), 0)).unwrap();
+
+        let (mut loaded, log_offset) = disk.load_snapshot().unwrap();
+        assert_eq!(loaded.nodes.len(), 2);
+        assert_eq!(loaded.last_id(), space.last_id());
+
+        //the reverse index is rebuilt so values are still shared
+        loaded.set(obj, "nickname", &Data::from("tim")).unwrap();
+        assert_eq!(loaded.nodes.len(), 2);
+
+        let tail: Vec<Transaction> = disk.load_transactions_from(log_offset).collect();
+        assert_eq!(tail.len(), 1);
+        assert_eq!(tail[0].key, "age");
+
+        //a snapshot that cannot be used is removed rather than tried again on every start
+        fs::write(format!("{}.snapshot", path), b"{").unwrap();
+        assert!(disk.load_snapshot().is_none());
+        assert!(!Path::new(format!("{}.snapshot", path).as_str()).exists());
+
+        let _ = fs::remove_file(path.as_str());
+        let _ = fs::remove_file(format!("{}.snapshot", path));
+    }
+
+    #[test]
+    fn convert() {
+        let bin = std::env::temp_dir().join("somedb_convert.bin");
+        let bin = bin.to_str().unwrap().to_owned();
+        let csv = format!("{}.csv", bin);
+        let _ = fs::remove_file(bin.as_str());
+        let _ = fs::remove_file(csv.as_str());
+
+        let mut transactions = vec![
+            Transaction::new(Command::Create, 0, String::new(), Data::Null, 0),
+            Transaction::new(Command::Set, 1, "name".to_owned(), Data::from("timothy \"the greate\", bourn"), 0),
+            Transaction::new(Command::Set, 1, "data".to_owned(), Data::Bytes(vec![0, 10, 255]), 0)
+        ];
+        transactions[1].ids = Some(vec![2]);
+        transactions[2].ids = Some(vec![]);
+        let disk = Disk::new(bin.as_str(), DiskFormat::Bin, 0, Durability::None, 0);
+        for t in &transactions {
+            disk.log_transaction(t).unwrap();
+        }
+
+        assert_eq!(Disk::convert(bin.as_str(), DiskFormat::Bin, csv.as_str(), DiskFormat::CSV), Ok(3));
+        let disk = Disk::new(csv.as_str(), DiskFormat::CSV, 0, Durability::None, 0);
+        let converted: Vec<Transaction> = disk.load_transactions().collect();
+        for (t, c) in transactions.iter().zip(converted.iter()) {
+            assert_eq!(t.to_bytes(), c.to_bytes());
+        }
+
+        //a row cut short by a crash is dropped like a binary record
+        let csv_size = fs::metadata(csv.as_str()).unwrap().len();
+        let mut file = fs::OpenOptions::new().append(true).open(csv.as_str()).unwrap();
+        file.write_all(b"1,1,\"na").unwrap();
+        assert_eq!(disk.load_transactions().count(), 3);
+        assert_eq!(fs::metadata(csv.as_str()).unwrap().len(), csv_size);
+
+        assert_eq!(Disk::convert(csv.as_str(), DiskFormat::CSV, bin.as_str(), DiskFormat::Bin), Ok(3));
+        assert_eq!(Disk::new(bin.as_str(), DiskFormat::Bin, 0, Durability::None, 0).load_transactions().count(), 3);
+
+        //a damaged file fails the conversion and leaves the file it would have replaced alone
+        let csv_bytes = fs::read(csv.as_str()).unwrap();
+        let mut bytes = fs::read(bin.as_str()).unwrap();
+        bytes[30] ^= 0xff;
+        fs::write(bin.as_str(), bytes).unwrap();
+        assert!(Disk::convert(bin.as_str(), DiskFormat::Bin, csv.as_str(), DiskFormat::CSV).is_err());
+        assert_eq!(fs::read(csv.as_str()).unwrap(), csv_bytes);
+        assert!(!Path::new(format!("{}.convert", csv).as_str()).exists());
+
+        //opening a file in the wrong format is refused instead of misread
+        assert!(Disk::open(csv.as_str(), DiskFormat::Bin, 0, Durability::None, 0).is_err());
+
+        let _ = fs::remove_file(bin.as_str());
+        let _ = fs::remove_file(csv.as_str());
+        let _ = fs::remove_file(format!("{}.corrupt", csv));
+    }
+}
@@ -0,0 +1,455 @@
+use std::sync::RwLock;
+
+use crate::space::{Space, Node};
+use crate::disk::Disk;
+use crate::config::Config;
+use crate::utils::*;
+
+//links cannot form a cycle, but a space written before that was refused could still hold one. The nodes on the
+//way down are tracked so reading never goes round in circles, and nesting deeper than MAX_DEPTH reads as null
+const MAX_DEPTH: usize = 256;
+
+fn prop_to_json(id: &usize, space: &Space, shallowmode: bool, path: &mut Vec<usize>) -> String {
+    match space.nodes.get(id) {
+        Some(Node::Value(v)) => v.val.to_json(),
+        Some(n) => {
+            if shallowmode {
+                id.to_string()
+            }else if path.contains(id) || path.len() >= MAX_DEPTH {
+                String::from("null")
+            }else {
+                node_to_json(*id, n, space, shallowmode, path)
+            }
+        },
+        None => String::from("null")
+    }
+}
+
+fn node_to_json(id: usize, node: &Node, space: &Space, shallowmode: bool, path: &mut Vec<usize>) -> String {
+    path.push(id);
+    let json = match node {
+        Node::Entity(ent) => {
+            let mut json = String::from("{");
+
+            for (name, id) in &ent.props {
+                let prop_str = format!("{}:{},", serde_json::to_string(name).unwrap(), prop_to_json(id, space, shallowmode, path));
+                json.push_str(prop_str.as_str());
+            }
+
+            if json.ends_with(',') {
+                json.pop();
+            }
+            json.push('}');
+
+            json
+        },
+        Node::List(list) => {
+            let items: Vec<String> = list.items.iter().map(|id| prop_to_json(id, space, shallowmode, path)).collect();
+            format!("[{}]", items.join(","))
+        },
+        Node::Value(v) => v.val.to_json()
+    };
+    path.pop();
+
+    json
+}
+
+fn _exec_read(space: &Space, t: &Transaction, keys: Vec<&str>, ki: usize, curr_obj: usize) -> Response {
+    let mut id_bytes = vec![0u8; Transaction::UINT_SIZE()];
+
+    if ki > keys.len() - 1 {
+        return Response::ok([id_bytes, Data::Null.to_bytes()].concat())
+    }
+
+    let curr_key = keys[ki];
+
+    let cmd = t.cmd.clone();
+    match t.cmd {
+        Command::Get | Command::GetRaw | Command::Len => {
+            match space.get(curr_obj, curr_key) {
+                Some((id, node)) => {
+                    id_bytes = write_usize(id);
+                    let last_key = ki == keys.len() - 1 || curr_key.len() == 0;
+                    match node {
+                        Node::Entity(_) | Node::List(_) if !last_key => {
+                            _exec_read(space, t, keys, ki+1, id)
+                        },
+                        Node::List(list) if cmd == Command::Len => {
+                            Response::ok([id_bytes, Data::U64(list.items.len() as u64).to_bytes()].concat())
+                        },
+                        _ if cmd == Command::Len => {
+                            Response::ok([id_bytes, Data::Null.to_bytes()].concat())
+                        },
+                        Node::Entity(_) | Node::List(_) => {
+                            let str = node_to_json(id, node, &space, cmd == Command::GetRaw, &mut vec![]);
+                            Response::ok([id_bytes, Data::Str(str).to_bytes()].concat())
+                        },
+                        Node::Value(v) => {
+                            if last_key {
+                                Response::ok([id_bytes, v.val.to_bytes()].concat())
+                            }else {
+                                Response::ok([id_bytes, Data::Null.to_bytes()].concat())
+                            }
+                        }
+                    }
+                },
+                None => Response::ok([id_bytes, Data::Null.to_bytes()].concat())
+            }
+        },
+        _ => panic!("wrong function buddy. You need execute_write"),
+    }
+}
+
+fn execute_read(space: &Space, t: &Transaction) -> Response {
+    _exec_read(space, t, t.key.split(".").collect(), 0, t.obj)
+}
+
+fn _exec_write(space: &mut Space, t: &Transaction, keys: Vec<&str>, ki: usize, curr_obj: usize) -> Response {
+    let mut id_bytes = vec![0u8; Transaction::UINT_SIZE()];
+
+    if ki > keys.len() - 1{
+        return Response::fail([id_bytes, "fail".as_bytes().to_vec()].concat())
+    }
+
+    let curr_key = keys[ki];
+
+    if ki == keys.len() - 1 || curr_key.len() == 0 {
+        let ok = [id_bytes.to_owned(), "ok".as_bytes().to_vec()].concat();
+
+        let written = match t.cmd {
+            Command::Create => Ok(write_usize(space.create())),
+            Command::Gc => {
+                let reclaimed = space.gc();
+                info!("gc reclaimed {} nodes", reclaimed);
+                Ok(write_usize(reclaimed))
+            },
+            Command::Set => space.set(curr_obj, curr_key, &t.val).map(|_| ok),
+            Command::Link => space.link(curr_obj, curr_key, t.othr).map(|_| ok),
+            Command::Delete => space.delete(curr_obj, curr_key).map(|_| ok),
+            Command::Unlink => space.unlink(curr_obj, curr_key).map(|_| ok),
+            Command::CreateList => Ok(write_usize(space.create_list())),
+            Command::Append => {
+                //the last key names the list itself rather than a slot inside it
+                let list = match space.get(curr_obj, curr_key) {
+                    Some((id, Node::List(_))) => id,
+                    _ => return Response::fail([id_bytes, "fail".as_bytes().to_vec()].concat())
+                };
+
+                space.append(list, &t.val, t.othr).map(|_| [write_usize(list), "ok".as_bytes().to_vec()].concat())
+            },
+            Command::PutJson => {
+                //the whole document is built under this one write lock and logged as this one transaction
+                match &t.val {
+                    Data::Str(s) => match serde_json::from_str(s.as_str()) {
+                        Ok(json) => space.put_json(&json).map(write_usize),
+                        Err(e) => Err(DbError::BadJson(e.to_string()))
+                    },
+                    _ => Err(DbError::BadJson(String::from("the document has to be sent as a string")))
+                }
+            },
+            Command::InsertAt => space.insert_at(curr_obj, curr_key, &t.val, t.othr).map(|_| ok),
+            Command::RemoveAt => space.remove_at(curr_obj, curr_key).map(|_| ok),
+            Command::Incr | Command::Decr => {
+                space.incr(curr_obj, curr_key, &t.val, t.cmd == Command::Decr).map(|(id, val)| [write_usize(id), val.to_bytes()].concat())
+            },
+            Command::CompareAndSet => {
+                //othr is the node the key is expected to point at, otherwise the value carries the expected value too
+                let (expected, val) = if t.othr != 0 {
+                    (Data::Null, t.val.to_owned())
+                }else {
+                    match t.compared() {
+                        Ok(pair) => pair,
+                        Err(e) => return Response::error(e)
+                    }
+                };
+
+                let (current_id, current, matches) = match space.get(curr_obj, curr_key) {
+                    Some((id, Node::Value(v))) => (id, v.val.to_owned(), if t.othr != 0 { id == t.othr } else { v.val == expected }),
+                    Some((id, _)) => (id, Data::Null, id == t.othr),
+                    None => (0, Data::Null, t.othr == 0 && expected == Data::Null)
+                };
+
+                if !matches {
+                    return Response::conflict([write_usize(current_id), current.to_bytes()].concat());
+                }
+
+                //the id of the new value, so the caller can compare against it next time
+                space.set(curr_obj, curr_key, &val).map(|_| match space.get(curr_obj, curr_key) {
+                    Some((id, _)) => write_usize(id),
+                    None => ok
+                })
+            },
+            _ => panic!("wrong function buddy. You need execute_read")
+        };
+
+        return match written {
+            Ok(data) => Response::ok(data),
+            Err(e) => Response::error(e)
+        }
+    }
+
+    let n = space.get(curr_obj, curr_key);
+    match n {
+        Some((id, node)) => {
+            id_bytes = write_usize(id);
+
+            match node {
+                Node::Entity(_) | Node::List(_) => {
+                    _exec_write(space, t, keys, ki+1, id)
+                }
+                Node::Value(v) => {
+                    Response::fail([id_bytes, "fail".as_bytes().to_vec()].concat())
+                }
+            }
+        },
+        None => Response::fail([id_bytes, "fail".as_bytes().to_vec()].concat())
+    }
+}
+
+fn execute_write(space: &mut Space, t: &Transaction) -> Response {
+    //a request never gets to pick the id of a node it makes
+    match t.cmd {
+        Command::Create | Command::CreateList | Command::Set if t.othr != 0 => {
+            return Response::error(DbError::Malformed(format!("{:?} takes no other object", t.cmd)));
+        },
+        _ => {}
+    };
+
+    _exec_write(space, t, t.key.split(".").collect(), 0, t.obj)
+}
+
+//the id an object id of a batch transaction stands for, see Transaction::BATCH_REF
+fn batch_ref(id: usize, ids: &[usize]) -> Result<usize, DbError> {
+    if id & Transaction::BATCH_REF == 0 {
+        return Ok(id);
+    }
+
+    match ids.get(id & !Transaction::BATCH_REF) {
+        Some(id) => Ok(id.to_owned()),
+        None => Err(DbError::Malformed(format!("batch reference {} points past the transactions before it", id & !Transaction::BATCH_REF)))
+    }
+}
+
+//applies the transactions of a batch until one is refused, the caller journals the space and rolls it back
+//unless the response is ok. the response holds the framed response of each transaction in order, or the
+//response of the one that was refused
+fn execute_batch(space: &mut Space, t: &Transaction) -> Response {
+    let transactions = match t.unbatch() {
+        Ok(ts) => ts,
+        Err(e) => return Response::error(e)
+    };
+
+    for bt in &transactions {
+        match bt.cmd {
+            Command::Create | Command::Set | Command::Link | Command::Delete | Command::Unlink |
+            Command::CreateList | Command::Append | Command::InsertAt | Command::RemoveAt | Command::PutJson |
+            Command::CompareAndSet | Command::Incr | Command::Decr => {},
+            cmd => return Response::error(DbError::NotBatchable(cmd))
+        }
+    }
+
+    let mut data: Vec<u8> = vec![];
+    let mut ids: Vec<usize> = vec![];
+    for bt in &transactions {
+        let resolved = match (batch_ref(bt.obj, &ids), batch_ref(bt.othr, &ids)) {
+            (Ok(obj), Ok(othr)) => Transaction::new(bt.cmd, obj, bt.key.to_owned(), bt.val.to_owned(), othr),
+            (Err(e), _) | (_, Err(e)) => return Response::error(e)
+        };
+
+        let resp = execute_write(space, &resolved);
+        if resp.status != Status::Ok {
+            return resp;
+        }
+
+        ids.push(read_usize(&resp.data));
+        data.append(&mut resp.to_bytes());
+    }
+
+    Response::ok(data)
+}
+
+//the database without the server, the space along with the file it is kept in. Every command the server
+//takes goes through execute, so an engine opened in process behaves the same as one reached over tcp
+pub struct Engine {
+    space: RwLock<Space>,
+    disk: RwLock<Disk>
+}
+
+impl Engine {
+    //opens the database file the config points at
+    pub fn open(config: &Config) -> Result<Engine, String> {
+        let disk = Disk::open(config.file_name.as_str(), config.file_format, config.compact_size, config.durability, config.snapshot_every)?;
+        Engine::load(disk)
+    }
+
+    //rebuilds the space from the latest snapshot and the transactions written after it. Fails when the file
+    //cannot be replayed as it was written, carrying on would leave a space that does not match it
+    pub fn load(disk: Disk) -> Result<Engine, String> {
+        //the latest snapshot spares replaying the part of the database file it covers
+        let (mut space, log_offset) = match disk.load_snapshot() {
+            Some((space, log_offset)) => {
+                info!("loaded snapshot of {} nodes covering {} bytes of the database file", space.nodes.len(), log_offset);
+                (space, log_offset)
+            },
+            None => (Space::new(), 0)
+        };
+
+        info!("loading transactions from database file");
+        let mut cnt: usize = 0;
+        let mut transactions = disk.load_transactions_from(log_offset);
+        for t in transactions.by_ref() {
+            let resp = match t.cmd {
+                Command::Get | Command::GetRaw | Command::Len => {
+                    execute_read(&space, &t)
+                },
+                Command::Create | Command::Set | Command::Link | Command::Delete | Command::Unlink | Command::Gc |
+                Command::CreateList | Command::Append | Command::InsertAt | Command::RemoveAt | Command::PutJson |
+                Command::CompareAndSet | Command::Incr | Command::Decr | Command::Batch => {
+                    if let Some(ids) = &t.ids {
+                        space.replay_ids(ids);
+                    }
+
+                    space.begin();
+                    let resp = match t.cmd {
+                        Command::Batch => execute_batch(&mut space, &t),
+                        _ => execute_write(&mut space, &t)
+                    };
+                    match resp.status {
+                        Status::Ok => space.commit(),
+                        _ => space.rollback()
+                    };
+
+                    //carrying on with nodes under other ids than the ones later transactions refer to would scramble the space
+                    let allocated = space.take_allocated();
+                    match &t.ids {
+                        Some(ids) if *ids != allocated => {
+                            return Err(format!("transaction {} of the database file was logged with ids {:?} but replaying it gave {:?}", cnt, ids, allocated));
+                        },
+                        _ => {}
+                    };
+
+                    resp
+                },
+                Command::Compact | Command::Snapshot | Command::Ping => Response::ok(vec![]) //none of them change the space, they are never logged
+            };
+
+            //only writes that went through are logged, one that is refused now means the space has drifted from the file
+            if resp.status != Status::Ok {
+                return Err(format!("transaction {} of the database file could not be replayed: {}", cnt, read_string(&resp.data)));
+            }
+            cnt += 1;
+        }
+        if let Some(why) = transactions.corrupt() {
+            return Err(why.to_owned());
+        }
+        info!("loaded {} transactions", cnt);
+
+        Ok(Engine {
+            space: RwLock::new(space),
+            disk: RwLock::new(disk)
+        })
+    }
+
+    pub fn execute(&self, t: Transaction) -> Response {
+        match t.cmd {
+            Command::Get | Command::GetRaw | Command::Len => {
+                let readable_space = match self.space.read() {
+                    Ok(s) => s,
+                    Err(e) => panic!("Space lock read error {}",e)
+                };
+
+                let resp = execute_read(&readable_space, &t);
+                resp
+            },
+            Command::Create | Command::Set | Command::Link | Command::Delete | Command::Unlink | Command::Gc |
+            Command::CreateList | Command::Append | Command::InsertAt | Command::RemoveAt | Command::PutJson | Command::Batch |
+            Command::CompareAndSet | Command::Incr | Command::Decr => {
+                let mut writeable_space = match self.space.write() {
+                    Ok(s) => s,
+                    Err(e) => panic!("Space lock write error {}",e)
+                };
+
+                //the write is journaled until it is in the database file so it can be undone if that fails
+                writeable_space.begin();
+                let resp = match t.cmd {
+                    Command::Batch => execute_batch(&mut writeable_space, &t),
+                    _ => execute_write(&mut writeable_space, &t)
+                };
+                let allocated = writeable_space.take_allocated();
+
+                //a write that was refused changed nothing so there is nothing to replay
+                if resp.status != Status::Ok {
+                    writeable_space.rollback();
+                    return resp;
+                }
+
+                //a counter is logged as the number it ended up at so replaying it cannot count twice
+                let mut logged = match t.cmd {
+                    Command::Incr | Command::Decr => match Data::from_bytes(&resp.data[Transaction::UINT_SIZE()..]) {
+                        Ok(val) => Transaction::new(Command::Set, t.obj, t.key.to_owned(), val, 0),
+                        Err(e) => panic!("{}", e)
+                    },
+                    _ => t
+                };
+                logged.ids = Some(allocated);
+
+                let (commit, seq) = match self.disk.write() {
+                    Ok(mut disk) => {
+                        let seq = match disk.log_transaction(&logged) {
+                            Ok(seq) => seq,
+                            Err(why) => {
+                                writeable_space.rollback();
+                                return Response::error(DbError::Disk(why));
+                            }
+                        };
+                        writeable_space.commit();
+
+                        if disk.needs_compaction() {
+                            disk.clean(&writeable_space);
+                        }else if disk.needs_snapshot() {
+                            disk.snapshot(&writeable_space);
+                        }
+
+                        (disk.group_commit(), seq)
+                    },
+                    Err(e) => panic!("Disk lock write error {}",e)
+                };
+
+                //the sync happens without the locks so other writers can get their transactions into the same one
+                drop(writeable_space);
+                commit.wait(seq);
+
+                resp
+            },
+            Command::Compact => {
+                let mut writeable_space = match self.space.write() {
+                    Ok(s) => s,
+                    Err(e) => panic!("Space lock write error {}",e)
+                };
+
+                match self.disk.write() {
+                    Ok(mut disk) => disk.clean(&writeable_space),
+                    Err(e) => panic!("Disk lock write error {}",e)
+                };
+
+                Response::ok([vec![0u8; Transaction::UINT_SIZE()], "ok".as_bytes().to_vec()].concat())
+            },
+            Command::Snapshot => {
+                //a snapshot only reads the space so reads carry on while it is written
+                let readable_space = match self.space.read() {
+                    Ok(s) => s,
+                    Err(e) => panic!("Space lock read error {}",e)
+                };
+
+                match self.disk.write() {
+                    Ok(mut disk) => disk.snapshot(&readable_space),
+                    Err(e) => panic!("Disk lock write error {}",e)
+                };
+
+                Response::ok([vec![0u8; Transaction::UINT_SIZE()], "ok".as_bytes().to_vec()].concat())
+            },
+            //lets a client check a connection is still good without touching the space
+            Command::Ping => Response::ok([vec![0u8; Transaction::UINT_SIZE()], "pong".as_bytes().to_vec()].concat())
+        }
+    }
+}
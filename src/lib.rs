@@ -0,0 +1,20 @@
+extern crate byteorder;
+extern crate serde;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+extern crate csv;
+extern crate sequencetree;
+extern crate somedb_client;
+#[macro_use]
+extern crate log;
+
+//the wire format is shared with the client crate
+pub use somedb_client::utils;
+
+pub mod space;
+pub mod disk;
+pub mod config;
+pub mod engine;
+
+pub use engine::Engine;
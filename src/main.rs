@@ -1,297 +1,221 @@
-extern crate byteorder;
-extern crate serde;
 #[macro_use]
 extern crate serde_json;
-#[macro_use]
-extern crate serde_derive;
-extern crate csv;
-extern crate sequencetree;
-
-use std::thread;
-use std::net::{TcpListener, TcpStream, Shutdown};
-use std::io::{Read, Write};
-use std::intrinsics::write_bytes;
-use threadpool::ThreadPool;
-use std::sync::{Arc, RwLock, PoisonError, RwLockReadGuard, RwLockWriteGuard};
-
-use crate::disk::DiskFormat;
-
-mod space;
-use space::{Space, Node, Entity, Value};
-
-mod utils;
-use utils::*;
-
-mod disk;
-use disk::Disk;
-
-mod client;
-mod config;
-
-use config::Config;
-
-fn ent_to_json(ent: &Entity, space: &Space, shallowmode: bool) -> String {
-    let mut json = String::from("{");
-
-    for (name, id) in &ent.props {
-        let prop_str = match space.nodes.get(id) {
-            Some(n) => {
-                match n {
-                    Node::Entity(sub) => {
-                        if shallowmode {
-                            format!("\"{}\": {},", name, id)
-                        }else {
-                            format!("\"{}\": {},", name, ent_to_json(sub, space, shallowmode))
-                        }
-                    },
-                    Node::Value(v) => format!("\"{}\":\"{}\",", name, v.val.escape_default().to_owned())
-                }
-            },
-            None => format!("\"{}\": null,", name)
-        };
 
-        json.push_str(prop_str.as_str());
-    }
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::{runtime, task};
 
-    json.pop();
-    json.push('}');
+use somedb::Engine;
+use somedb::utils::*;
+use somedb::disk::{Disk, DiskFormat};
+use somedb::config::Config;
 
-    json
-}
+//reads one framed request, a request that is cut short ends the connection while one that cannot be parsed is answered.
+//A request bigger than max_frame is answered too but ends the connection, its bytes are never read
+async fn connection_to_transaction (stream: &mut TcpStream, peer: &SocketAddr, max_frame: usize) -> Result<Result<Transaction, DbError>, String> {
+    let mut data_size_buf = [0u8; 8];
 
-fn _exec_read(space: &RwLockReadGuard<Space>, t: &Transaction, keys: Vec<&str>, ki: usize, curr_obj: usize) -> Vec<u8> {
-    let mut id_bytes = vec![0u8; Transaction::UINT_SIZE()];
+    let data_size = match stream.read_exact(&mut data_size_buf).await {
+        Ok(_) => {
+            let data_size = read_usize(&data_size_buf);
+            println!("[RX] {} ( {} bytes )", peer, data_size);
+            data_size
+        },
+        Err(e) => return Err(format!("{}", e))
+    };
 
-    if ki > keys.len() - 1 {
-        println!("exit 1 {}/{} {:?}", ki, keys.len(), keys);
-        return [id_bytes, "null".as_bytes().to_vec()].concat()
+    if data_size > max_frame {
+        let resp = Response::error(DbError::Malformed(format!("request of {} bytes is over the {} byte limit", data_size, max_frame)));
+        let _ = stream.write_all(resp.to_bytes().as_slice()).await;
+        return Err(format!("it sent a request of {} bytes", data_size));
     }
 
-    let curr_key = keys[ki];
-
-    let cmd = t.cmd.clone();
-    match t.cmd {
-        Command::Get | Command::GetRaw => {
-            let n = space.get(curr_obj, curr_key);
-            println!("{:?} {} '{}'",n,curr_obj,curr_key);
-            match n {
-                Some((id, node)) => {
-                    id_bytes = write_usize(id);
-                    match node {
-                        Node::Entity(ent) => {
-                            if ki == keys.len() - 1 || curr_key.len() == 0 {
-                                let str = ent_to_json(ent, &space, cmd == Command::GetRaw);
-                                [id_bytes, write_string(str)].concat()
-                            }else {
-                                _exec_read(space, t, keys, ki+1, id)
-                            }
-                        }
-                        Node::Value(v) => {
-                            if ki == keys.len() - 1 || curr_key.len() == 0 {
-                                [id_bytes, write_string(v.val.to_owned())].concat()
-                            }else {
-                                println!("exit 2 {}/{} {:?}", ki, keys.len(), keys);
-                                [id_bytes, "null".as_bytes().to_vec()].concat()
-                            }
-                        }
-                    }
-                },
-                None => { println!("exit 3 {}/{} {:?}", ki, keys.len(), keys); [id_bytes, "null".as_bytes().to_vec()].concat() }
-            }
-        },
-        _ => panic!("wrong function buddy. You need execute_write"),
+    let mut data = vec![0u8; data_size];
+    match stream.read_exact(&mut data).await {
+        Ok(_) => Ok(Transaction::from(data)),
+        Err(e) => Err(format!("{}", e))
     }
 }
 
-fn execute_read(space: &RwLockReadGuard<Space>, t: &Transaction) -> Vec<u8> {
-    _exec_read(space, t, t.key.split(".").collect(), 0, t.obj)
-}
-
-fn _exec_write(space: &mut RwLockWriteGuard<Space>, t: &Transaction, keys: Vec<&str>, ki: usize, curr_obj: usize) -> Vec<u8> {
-    let mut id_bytes = vec![0u8; Transaction::UINT_SIZE()];
-
-    if ki > keys.len() - 1{
-        return [id_bytes, "fail".as_bytes().to_vec()].concat()
+//the connection stays open for as many transactions as the client sends, each answered in order
+async fn handle_connection(mut stream: TcpStream, peer: SocketAddr, engine: Arc<Engine>, max_frame: usize) {
+    if let Err(e) = stream.set_nodelay(true) {
+        println!("Error: {}", e);
     }
 
-    let curr_key = keys[ki];
-
-    if ki == keys.len() - 1 || curr_key.len() == 0 {
-        return match t.cmd {
-            Command::Create => {
-
-                let new_obj = space.create();
-                write_usize(new_obj)
-            },
-            Command::Set => {
-                space.set(curr_obj, curr_key, t.val.as_str());
-                [id_bytes, "ok".as_bytes().to_vec()].concat()
+    loop {
+        let resp = match connection_to_transaction(&mut stream, &peer, max_frame).await {
+            Ok(Ok(t)) => {
+                //executing takes the space lock and can wait for the disk, so it runs off the threads serving connections
+                let engine = Arc::clone(&engine);
+                match task::spawn_blocking(move || engine.execute(t)).await {
+                    Ok(resp) => resp.to_bytes(),
+                    Err(e) => {
+                        println!("closing connection with {} because executing its request failed: {}", peer, e);
+                        return;
+                    }
+                }
             },
-            Command::Link => {
-                space.link(curr_obj, curr_key, t.othr);
-                [id_bytes,"ok".as_bytes().to_vec()].concat()
+            Ok(Err(e)) => {
+                println!("bad request from {}: {}", peer, e);
+                Response::error(e).to_bytes()
             },
-            _ => panic!("wrong function buddy. You need execute_read")
+            Err(e) => {
+                println!("closing connection with {} because {}", peer, e);
+                let _ = stream.shutdown().await;
+                return;
+            }
+        };
+
+        println!("[TX] {} ( {} bytes )", peer, resp.len());
+        if let Err(e) = stream.write_all(resp.as_slice()).await {
+            println!("TX error occurred, terminating connection with {} because {}", peer, e);
+            return;
         }
     }
+}
 
-    let n = space.get(curr_obj, curr_key);
-    match n {
-        Some((id, node)) => {
-            id_bytes = write_usize(id);
-
-            match node {
-                Node::Entity(ent) => {
-                    _exec_write(space, t, keys, ki+1, id)
-                }
-                Node::Value(v) => {
-                    [id_bytes, "fail".as_bytes().to_vec()].concat()
-                }
-            }
-        },
-        None => [id_bytes, "fail".as_bytes().to_vec()].concat()
+async fn serve(listener: TcpListener, max_frame: usize, engine: Arc<Engine>) {
+    //every connection is a task of its own, one that sits idle holds no thread
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                tokio::spawn(handle_connection(stream, peer, Arc::clone(&engine), max_frame));
+            },
+            Err(e) => println!("Error: {}", e)
+        };
     }
 }
 
-fn execute_write(space: &mut RwLockWriteGuard<Space>, t: &Transaction) -> Vec<u8> {
-    _exec_write(space, t, t.key.split(".").collect(), 0, t.obj)
+fn parse_format(s: &str) -> DiskFormat {
+    match s {
+        "Bin" | "bin" => DiskFormat::Bin,
+        "CSV" | "csv" => DiskFormat::CSV,
+        _ => {
+            println!("unknown file format {}, use Bin or CSV", s);
+            std::process::exit(1);
+        }
+    }
 }
 
-fn connection_to_transaction (stream: &mut TcpStream) -> Result<Transaction, String> {
-    let mut data_size = 0;
-    let mut data_size_buf = [0u8; 8];
+//the library logs what it does to the database file, the server prints it along with its own output
+struct StdoutLogger;
 
-    match stream.read_exact(&mut data_size_buf) {
-        Ok(_) => {
-            data_size = read_usize(&data_size_buf);
-            println!("[RX] {} ( {} bytes )", stream.peer_addr().unwrap(), data_size);
-        },
-        Err(e) => return Err(format!("{}", e))
-    };
+impl log::Log for StdoutLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= log::Level::Info
+    }
 
-    let mut data = vec![0u8; data_size];
-    match stream.read_exact(&mut data) {
-        Ok(_) => Ok(Transaction::from(data)),
-        Err(e) => Err(format!("{}", e))
+    fn log(&self, record: &log::Record) {
+        if self.enabled(record.metadata()) {
+            println!("{}", record.args());
+        }
     }
+
+    fn flush(&self) {}
 }
 
-fn main() {
-    let config = Config::new();
-    let mut space = Space::new();
-    let disk = Disk::new(config.file_name.as_str(), config.file_format);
-
-    let threadpool = ThreadPool::new(config.threads);
-
-    let space_lock = Arc::new(RwLock::new(space));
-    let disk_lock = Arc::new(RwLock::new(disk));
-
-    println!("loading transactions from database file");
-    let mut cnt: usize = 0;
-    for t in disk_lock.read().unwrap().load_transactions() {
-        match t.cmd {
-            Command::Get | Command::GetRaw => {
-                let readable_space = match space_lock.read() {
-                    Ok(s) => s,
-                    Err(e) => panic!("Space lock read error {}",e)
-                };
-
-                let resp = execute_read(&readable_space, &t);
-                resp
-            },
-            Command::Create | Command::Set | Command::Link => {
-                let mut writeable_space = match space_lock.write() {
-                    Ok(s) => s,
-                    Err(e) => panic!("Space lock write error {}",e)
-                };
-                let resp = execute_write(&mut writeable_space, &t);
-
-                resp
-            }
-        };
-        cnt += 1;
+static LOGGER: StdoutLogger = StdoutLogger;
+
+//somedb convert <file> <format> <new file> <format>
+fn convert(args: &[String]) {
+    if args.len() != 4 {
+        println!("usage: somedb convert <database file> <Bin|CSV> <new database file> <Bin|CSV>");
+        std::process::exit(1);
     }
-    println!("loaded {} transactions. starting server", cnt);
-
-    let addr = format!("0.0.0.0:{}", config.port);
-    let listener = TcpListener::bind(addr.as_str()).unwrap();
-    // accept connections and process them, spawning a new thread for each one
-    println!("Server listening on port {}", config.port);
-
-    for connection in listener.incoming() {
-        let space_lock_clone = Arc::clone(&space_lock);
-        let disk_lock_clone = Arc::clone(&disk_lock);
-
-        threadpool.execute(move || {
-            let mut stream = match connection {
-                Ok(s) => s,
-                Err(e) => {
-                    println!("Error: {}", e);
-                    return;
-                }
-            };
-
-            let t = match connection_to_transaction(&mut stream) {
-                Ok(t) => t,
-                Err(e) => {
-                    println!("RX error occurred, terminating connection with {} because {}", stream.peer_addr().unwrap(), e);
-                    stream.shutdown(Shutdown::Both).unwrap();
-                    return;
-                }
-            };
 
-            let resp = match t.cmd {
-                Command::Get | Command::GetRaw => {
-                    let readable_space = match space_lock_clone.read() {
-                        Ok(s) => s,
-                        Err(e) => panic!("Space lock read error {}",e)
-                    };
+    match Disk::convert(args[0].as_str(), parse_format(args[1].as_str()), args[2].as_str(), parse_format(args[3].as_str())) {
+        Ok(cnt) => println!("converted {} transactions from {} to {}", cnt, args[0], args[2]),
+        Err(why) => {
+            println!("couldn't convert {}: {}", args[0], why);
+            std::process::exit(1);
+        }
+    };
+}
 
-                    let resp = execute_read(&readable_space, &t);
-                    resp
-                },
-                Command::Create | Command::Set | Command::Link => {
-                    let mut writeable_space = match space_lock_clone.write() {
-                        Ok(s) => s,
-                        Err(e) => panic!("Space lock write error {}",e)
-                    };
+fn main() {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(log::LevelFilter::Info);
+    }
 
-                    let resp = execute_write(&mut writeable_space, &t);
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() > 1 && args[1] == "convert" {
+        convert(&args[2..]);
+        return;
+    }
 
-                    match disk_lock_clone.write() {
-                        Ok(disk) => disk.log_transaction(&t),
-                        Err(e) => panic!("Disk lock write error {}",e)
-                    };
+    let config = Config::new();
+    let engine = match Engine::open(&config) {
+        Ok(engine) => Arc::new(engine),
+        Err(why) => panic!("couldn't open the database: {}", why)
+    };
 
-                    resp
-                }
-            };
+    //threads caps how many requests are executed at once, not how many connections can be open
+    let runtime = match runtime::Builder::new_multi_thread().enable_io().max_blocking_threads(config.threads).build() {
+        Ok(r) => r,
+        Err(e) => panic!("couldn't start the runtime: {}", e)
+    };
 
-            println!("[TX] {} ( {} bytes )",stream.peer_addr().unwrap(), resp.len());
-            stream.write(resp.as_slice()).unwrap();
-        });
+    runtime.block_on(async {
+        let addr = format!("0.0.0.0:{}", config.port);
+        let listener = match TcpListener::bind(addr.as_str()).await {
+            Ok(l) => l,
+            Err(e) => panic!("couldn't listen on {}: {}", addr, e)
+        };
+        println!("Server listening on port {}", config.port);
 
-    }
-    // close the socket server
-    drop(listener);
+        serve(listener, config.max_frame, engine).await
+    });
 }
-
 #[cfg(test)]
 mod tests {
-    use crate::client::Client;
-    use crate::utils::*;
+    use somedb_client::{Client, AsyncClient, Error};
+    use somedb::utils::*;
+    use somedb::space::{Space, Node};
+    use somedb::Engine;
+    use somedb::disk::{Disk, DiskFormat, Durability};
     use serde_json::Value;
-    
+    use serde_derive::{Serialize, Deserialize};
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+    use std::sync::Arc;
+
+    //a server on a free port over a database file of its own, so every test that goes through a client
+    //starts from an empty space and the tests can run side by side
+    fn start_server(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("somedb_server_{}.bin", name));
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(path.as_str());
+        let engine = Engine::load(Disk::new(path.as_str(), DiskFormat::Bin, 0, Durability::None, 0)).unwrap();
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        listener.set_nonblocking(true).unwrap();
+        std::thread::spawn(move || {
+            let runtime = tokio::runtime::Builder::new_multi_thread().enable_io().build().unwrap();
+            runtime.block_on(async {
+                let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+                super::serve(listener, 1 << 20, Arc::new(engine)).await
+            });
+        });
+
+        addr
+    }
+
     #[test]
     fn fetch() {
-        let db = Client::new("localhost:4000");
+        let db = Client::new(start_server("fetch").as_str());
         //db.set(1, "achivements.1.title", "aob-new");
         println!("{:?}", db.get_str(1, "achivements.0"));
     }
 
     #[test]
     fn save_json() {
-        let db = Client::new("localhost:4000");
+        let addr = start_server("save_json");
+        let db = Client::new(addr.as_str());
 
         fn encode_json(obj: Value, db: &Client) -> String {
             match obj {
@@ -300,34 +224,29 @@ mod tests {
                 Value::Number(val) => val.to_string(),
                 Value::Null => String::from("null"),
                 Value::Array(arr) => {
-                    let dbarr = db.create();
+                    let dbarr = db.create_list().unwrap();
 
-                    let mut i= 0;
                     for val in arr {
                         let v = encode_json(val.to_owned(), db);
-                        let key = format!("{}", i);
 
                         match val {
-                            Value::Object(_) => db.link(dbarr, key.as_str(), v.parse::<usize>().unwrap()),
-                            Value::Array(_) => db.link(dbarr, key.as_str(), v.parse::<usize>().unwrap()),
-                            _=> db.set(dbarr, key.as_str(), v.as_str())
-                        };
-
-                        i += 1;
+                            Value::Object(_) | Value::Array(_) => db.append_link(dbarr, "", v.parse::<usize>().unwrap()),
+                            _=> db.append(dbarr, "", Data::from(&val))
+                        }.unwrap();
                     }
 
                     dbarr.to_string()
                 },
                 Value::Object(obj) => {
-                    let dbobj: usize = db.create();
+                    let dbobj: usize = db.create().unwrap();
 
                     for (key, val) in obj {
                         let v = encode_json(val.to_owned(), db);
 
                         match val {
                             Value::Object(_) | Value::Array(_) => db.link(dbobj, key.as_str(), v.parse::<usize>().unwrap()),
-                            _=> db.set(dbobj, key.as_str(), v.as_str())
-                        };
+                            _=> db.set(dbobj, key.as_str(), Data::from(&val))
+                        }.unwrap();
                     }
 
                     dbobj.to_string()
@@ -376,32 +295,407 @@ mod tests {
         let id = encode_json(val, &db);
 
         println!("object id = {}", id);
+        println!("{:?}", db.get_str(id.parse::<usize>().unwrap(), "achivements.2.title"));
+        println!("{:?}", db.len(id.parse::<usize>().unwrap(), "achivements"));
+
+        let id2 = db.put_json(&val2).unwrap();
+        println!("object id = {}", id2);
+        println!("{:?}", db.get_obj(id2));
     }
 
     #[test]
     fn client() {
-        let db = Client::new("localhost:4000");
-        let obj = db.create();
+        let addr = start_server("client");
+        let db = Client::new(addr.as_str());
+        let obj = db.create().unwrap();
         println!("obj {}", obj);
 
-        let sub_obj = db.create();
-        println!("sub_obj {:?}", sub_obj);
-        println!("set {:?}", db.set(sub_obj, "age", "55"));
-        println!("set {:?}", db.set(sub_obj, "name", "timothy \"the greate\" bourn"));
+        let sub_obj = db.create().unwrap();
+        db.set(sub_obj, "age", 55u64).unwrap();
+        db.set(sub_obj, "name", "timothy \"the greate\" bourn").unwrap();
+
+        db.link(obj, "child", sub_obj).unwrap();
+        db.link(obj, "child_copy", sub_obj).unwrap();
+        db.unlink(obj, "child_copy").unwrap();
+        assert_eq!(db.get(obj, "child_copy").unwrap(), (0, Data::Null));
+        assert_eq!(db.get_usize(obj, "child.age").unwrap(), (db.get(sub_obj, "age").unwrap().0, Some(55)));
+
+        db.delete(obj, "child.age").unwrap();
+        assert_eq!(db.get(obj, "child.age").unwrap().1, Data::Null);
+        assert_eq!(db.get_str(obj, "child.name").unwrap().1, "timothy \"the greate\" bourn");
+
+        //deleting one key leaves the object to the other keys that link to it
+        db.link(obj, "child_copy", sub_obj).unwrap();
+        db.delete(obj, "child").unwrap();
+        assert_eq!(db.get(obj, "child").unwrap().1, Data::Null);
+        assert_eq!(db.get_str(obj, "child_copy.name").unwrap().1, "timothy \"the greate\" bourn");
+
+        //so does deleting it by its id, and a value shared through the reverse index can not be deleted by id
+        assert!(match db.delete(sub_obj, "") { Err(Error::Server(_)) => true, _ => false });
+        let (name_id, _) = db.get(sub_obj, "name").unwrap();
+        assert!(match db.delete(name_id, "") { Err(Error::Server(_)) => true, _ => false });
+        db.set(obj, "name", "timothy \"the greate\" bourn").unwrap();
+        assert_eq!(db.get(obj, "name").unwrap().0, name_id);
+
+        db.unlink(obj, "child_copy").unwrap();
+        db.delete(sub_obj, "").unwrap();
+        assert_eq!(db.get_obj(sub_obj).unwrap().1, "null");
+
+        let long = "x".repeat(5000);
+        db.set(obj, "long", long.as_str()).unwrap();
+        assert_eq!(db.get_str(obj, "long").unwrap().1, long);
+
+        //a key that is not there is an error rather than a panic
+        assert!(match db.set(obj, "missing.age", 55u64) { Err(Error::NotFound) => true, _ => false });
+        assert!(match Client::new("localhost:1").create() { Err(Error::Io(_)) => true, _ => false });
+    }
 
-        println!("link {:?}", db.link(obj, "child", sub_obj));
+    #[test]
+    fn typed_objects() {
+        let addr = start_server("typed_objects");
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Achievement {
+            title: String,
+            hits: u64
+        }
+
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Person {
+            name: String,
+            age: i64,
+            ratio: f64,
+            employed: bool,
+            nickname: Option<String>,
+            tags: Vec<String>,
+            achievements: Vec<Achievement>
+        }
 
-        println!("get {:?}", db.get_str(obj, "child"));
+        let db = Client::new(addr.as_str());
+        let person = Person {
+            name: "amit \"the dev\"".to_owned(),
+            age: 24,
+            ratio: 0.5,
+            employed: true,
+            nickname: None,
+            tags: vec!["rust".to_owned(), "db".to_owned()],
+            achievements: vec![Achievement { title: "somedb".to_owned(), hits: 45312 }]
+        };
+
+        let id = db.store(&person).unwrap();
+        assert_eq!(db.get_str(id, "achievements.0.title").unwrap().1, "somedb");
+        assert_eq!(db.load::<Person>(id).unwrap(), person);
+        assert!(match db.store(&55u64) { Err(Error::Json(_)) => true, _ => false });
+
+        //keys that would read back as a different path are refused before anything is sent
+        let mut dotted = std::collections::HashMap::new();
+        dotted.insert("version.major", 1u64);
+        assert!(match db.store(&vec![dotted]) { Err(Error::Json(_)) => true, _ => false });
+        let mut empty = std::collections::HashMap::new();
+        empty.insert("", 1u64);
+        assert!(match db.store(&empty) { Err(Error::Json(_)) => true, _ => false });
+    }
+
+    #[test]
+    fn pooled_client() {
+        let addr = start_server("pooled_client");
+        let db = Arc::new(Client::pooled(addr.as_str(), 2).check_after(std::time::Duration::from_millis(0)));
+        db.ping().unwrap();
+        let obj = db.create().unwrap();
+
+        //more threads than connections, the ones left over wait for a connection to be put back
+        let threads: Vec<_> = (0..4).map(|_| {
+            let db = Arc::clone(&db);
+            std::thread::spawn(move || {
+                for _ in 0..25 {
+                    db.incr(obj, "hits", Data::Null).unwrap();
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(db.get_usize(obj, "hits").unwrap().1, Some(100));
+
+        assert!(match Client::pooled("localhost:1", 2).ping() { Err(Error::Io(_)) => true, _ => false });
+    }
+
+    #[test]
+    fn pooled_client_retry() {
+        use std::io::Read;
+        use std::time::Duration;
+
+        //answers one ping a little late, so two requests at once need two connections
+        fn answer(stream: &mut std::net::TcpStream) -> bool {
+            let mut size = [0u8; 8];
+            if stream.read_exact(&mut size).is_err() {
+                return false;
+            }
+            let mut data = vec![0u8; read_usize(&size)];
+            stream.read_exact(&mut data).unwrap();
+            std::thread::sleep(Duration::from_millis(50));
+            stream.write_all(&Response::ok(vec![]).to_bytes()).is_ok()
+        }
+
+        //the first two connections are closed after their first request, like a server that restarted
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                std::thread::spawn(move || {
+                    if i < 2 {
+                        answer(&mut stream);
+                    }else {
+                        while answer(&mut stream) {}
+                    }
+                });
+            }
+        });
+
+        let db = Arc::new(Client::pooled(addr.as_str(), 2));
+        let other = Arc::clone(&db);
+        let t = std::thread::spawn(move || other.ping().unwrap());
+        db.ping().unwrap();
+        t.join().unwrap();
+
+        //both idle connections are stale, the retried read does not land on the second one
+        std::thread::sleep(Duration::from_millis(100));
+        db.ping().unwrap();
+    }
+
+    #[tokio::test]
+    async fn async_client() {
+        let addr = start_server("async_client");
+        let db = AsyncClient::new(addr.as_str());
+        let obj = db.create().await.unwrap();
+        db.set(obj, "name", "tim").await.unwrap();
+        assert_eq!(db.incr(obj, "clicks", Data::Null).await.unwrap().1, Data::U64(1));
+        assert_eq!(db.get_str(obj, "name").await.unwrap().1, "tim");
+        assert!(match db.set(obj, "missing.age", 55u64).await { Err(Error::NotFound) => true, _ => false });
+
+        let mut b = db.begin();
+        let sub_obj = b.create();
+        b.set(sub_obj, "long", "x".repeat(5000));
+        b.link(obj, "child", sub_obj);
+        assert_eq!(b.commit().await.unwrap().len(), 3);
+        assert_eq!(db.get_str(obj, "child.long").await.unwrap().1.len(), 5000);
+
+        //a request dropped before its response arrives does not leave that response for the next one, which
+        //would read the object instead of its own number
+        db.set(obj, "visits", 7u64).await.unwrap();
+        let (visits_id, _) = db.get(obj, "visits").await.unwrap();
+        for micros in [0, 50, 200, 1000] {
+            let cancelled = tokio::time::timeout(std::time::Duration::from_micros(micros), db.get_obj(obj)).await;
+            assert!(cancelled.is_err() || cancelled.unwrap().is_ok());
+            assert_eq!(db.get(obj, "visits").await.unwrap(), (visits_id, Data::U64(7)));
+            assert_eq!(db.get_usize(obj, "clicks").await.unwrap().1, Some(1));
+        }
     }
 
     #[test]
     fn serialize() {
-        let t = Transaction::new(Command::from(3), 15453332589748683533, "child".to_owned(), String::new(), 8693387624441552404);
+        let t = Transaction::new(Command::from(3).unwrap(), 15453332589748683533, "child".to_owned(), Data::Null, 8693387624441552404);
         let nt = t.to_bytes();
-        let gt = Transaction::from(nt);
+        let gt = Transaction::from(nt).unwrap();
 
         println!("t = {:?}\ngt = {:?}",t, gt);
 
         assert!(true);
+
+        assert_eq!(Command::from(200), Err(DbError::UnsupportedCommand(200)));
+        assert!(Transaction::from(vec![1, 0, 0]).is_err());
+        assert!(Data::from_bytes(&[1, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn typed_values() {
+        let values = vec![
+            Data::Str("timothy \"the greate\" bourn".to_owned()),
+            Data::U64(u64::max_value()),
+            Data::I64(-55),
+            Data::F64(0.25),
+            Data::Bool(true),
+            Data::Null,
+            Data::Bytes(vec![0, 1, 254, 255])
+        ];
+
+        for val in values {
+            assert_eq!(Data::from_bytes(val.to_bytes().as_slice()), Ok(val.clone()));
+            assert_eq!(Data::from_tagged(val.to_tagged().as_str()), val);
+        }
+
+        assert_eq!(Data::from_bytes("legacy".as_bytes()), Ok(Data::Str("legacy".to_owned())));
+        assert_eq!(Data::from_tagged("http://legacy"), Data::Str("http://legacy".to_owned()));
+    }
+
+    #[test]
+    fn oversized_request() {
+        use std::io::Read;
+
+        //the server refuses to allocate what the client announces and hangs up
+        let mut stream = std::net::TcpStream::connect(start_server("oversized_request")).unwrap();
+        stream.write_all(&write_usize(usize::MAX)).unwrap();
+
+        let mut resp_size = [0u8; 8];
+        stream.read_exact(&mut resp_size).unwrap();
+        let mut data = vec![0u8; read_usize(&resp_size)];
+        stream.read_exact(&mut data).unwrap();
+        assert_eq!(Response::from(data).unwrap().status, Status::Error);
+        assert_eq!(stream.read(&mut resp_size).unwrap(), 0);
+    }
+
+    #[test]
+    fn batch() {
+        let addr = start_server("batch");
+        let db = Client::new(addr.as_str());
+        let mut b = db.begin();
+        let obj = b.create();
+        b.set(obj, "name", "tim");
+        let sub_obj = b.create();
+        b.set(sub_obj, "age", 55u64);
+        b.link(obj, "child", sub_obj);
+        let resps = b.commit().unwrap();
+        assert_eq!(resps.len(), 5);
+
+        let obj = read_usize(&resps[0].data);
+        assert_eq!(db.get_usize(obj, "child.age").unwrap().1, Some(55));
+
+        let mut b = db.begin();
+        b.set(obj, "name", "bob");
+        b.delete(obj + 1000, "name");
+        assert!(b.commit().is_err());
+        assert_eq!(db.get_str(obj, "name").unwrap().1, "tim");
+    }
+
+    #[test]
+    fn compare_and_set() {
+        let addr = start_server("compare_and_set");
+        let db = Client::new(addr.as_str());
+        let obj = db.create().unwrap();
+
+        assert!(db.compare_and_set(obj, "clicks", Data::Null, 1u64).is_ok());
+        let set = db.compare_and_set(obj, "clicks", 1u64, 2u64).unwrap();
+        assert_eq!(set, db.get(obj, "clicks").unwrap().0);
+
+        let (id, current) = match db.compare_and_set(obj, "clicks", 1u64, 3u64) {
+            Err(Error::Conflict(id, current)) => (id, current),
+            other => panic!("{:?}", other)
+        };
+        assert_eq!(current, Data::U64(2));
+        assert!(db.compare_and_set_node(obj, "clicks", id, 3u64).is_ok());
+        assert!(db.compare_and_set_node(obj, "clicks", id, 4u64).is_err());
+        assert_eq!(db.get_usize(obj, "clicks").unwrap().1, Some(3));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn incr() {
+        let addr = start_server("incr");
+        let db = Client::new(addr.as_str());
+        let obj = db.create().unwrap();
+
+        assert_eq!(db.incr(obj, "clicks", Data::Null).unwrap().1, Data::U64(1));
+        assert_eq!(db.incr(obj, "clicks", 10u64).unwrap().1, Data::U64(11));
+        assert_eq!(db.decr(obj, "clicks", 12u64).unwrap().1, Data::I64(-1));
+        assert_eq!(db.incr(obj, "ratio", 0.5).unwrap().1, Data::F64(0.5));
+
+        let threads: Vec<_> = (0..4).map(|_| {
+            let addr = addr.to_owned();
+            std::thread::spawn(move || {
+                let db = Client::new(addr.as_str());
+                for _ in 0..25 {
+                    db.incr(obj, "hits", Data::Null).unwrap();
+                }
+            })
+        }).collect();
+        for t in threads {
+            t.join().unwrap();
+        }
+        assert_eq!(db.get_usize(obj, "hits").unwrap().1, Some(100));
+
+    }
+
+    #[test]
+    fn compact() {
+        let path = std::env::temp_dir().join("somedb_compact.bin");
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(path.as_str());
+        let _ = fs::remove_file(format!("{}.snapshot", path));
+
+        let open = || Engine::load(Disk::new(path.as_str(), DiskFormat::Bin, 0, Durability::Always, 0)).unwrap();
+        let run = |engine: &Engine, cmd: Command, obj: usize, key: &str, val: Data, othr: usize| {
+            let resp = engine.execute(Transaction::new(cmd, obj, key.to_owned(), val, othr));
+            assert_eq!(resp.status, Status::Ok);
+            resp.data
+        };
+
+        let engine = open();
+        let obj = read_usize(&run(&engine, Command::Create, 0, "", Data::Null, 0));
+        let child = read_usize(&run(&engine, Command::Create, 0, "", Data::Null, 0));
+        run(&engine, Command::Set, child, "age", Data::U64(55), 0);
+        run(&engine, Command::Link, obj, "child", Data::Null, child);
+        run(&engine, Command::Unlink, obj, "child", Data::Null, 0);
+        run(&engine, Command::Set, obj, "name", Data::from("tim"), 0);
+        run(&engine, Command::Set, obj, "name", Data::from("timothy"), 0);
+        run(&engine, Command::Compact, 0, "", Data::Null, 0);
+        drop(engine);
+
+        //compacting reclaims nothing, the detached child and the old name are there until gc runs
+        let engine = open();
+        let age = run(&engine, Command::Get, child, "age", Data::Null, 0);
+        assert_eq!(Data::from_bytes(&age[Transaction::UINT_SIZE()..]).unwrap(), Data::U64(55));
+        let name = run(&engine, Command::Get, obj, "name", Data::Null, 0);
+        assert_eq!(Data::from_bytes(&name[Transaction::UINT_SIZE()..]).unwrap(), Data::from("timothy"));
+        assert_eq!(read_usize(&run(&engine, Command::Gc, 0, "", Data::Null, 0)), 3);
+        assert_eq!(read_usize(&run(&engine, Command::Create, 0, "", Data::Null, 0)), 6);
+        drop(engine);
+
+        let _ = fs::remove_file(path.as_str());
+        let _ = fs::remove_file(format!("{}.snapshot", path));
+    }
+
+    #[test]
+    fn engine() {
+        let path = std::env::temp_dir().join("somedb_engine.bin");
+        let path = path.to_str().unwrap().to_owned();
+        let _ = fs::remove_file(path.as_str());
+        let _ = fs::remove_file(format!("{}.snapshot", path));
+
+        let engine = Engine::load(Disk::new(path.as_str(), DiskFormat::Bin, 0, Durability::Always, 0)).unwrap();
+        let resp = engine.execute(Transaction::new(Command::Create, 0, String::new(), Data::Null, 0));
+        assert_eq!(resp.status, Status::Ok);
+        let obj = read_usize(resp.data.as_slice());
+        assert_eq!(engine.execute(Transaction::new(Command::Set, obj, "name".to_owned(), Data::from("tim"), 0)).status, Status::Ok);
+        assert_eq!(engine.execute(Transaction::new(Command::Set, 99, "name".to_owned(), Data::from("tim"), 0)).status, Status::Error);
+        //a request cannot pick the id of the node it makes
+        assert_eq!(engine.execute(Transaction::new(Command::Create, 0, String::new(), Data::Null, usize::MAX)).status, Status::Error);
+        assert_eq!(engine.execute(Transaction::new(Command::Set, obj, "age".to_owned(), Data::from(3u64), usize::MAX)).status, Status::Error);
+        drop(engine);
+
+        //a reopened engine replays what the first one logged
+        let engine = Engine::load(Disk::new(path.as_str(), DiskFormat::Bin, 0, Durability::Always, 0)).unwrap();
+        let resp = engine.execute(Transaction::new(Command::Get, obj, "name".to_owned(), Data::Null, 0));
+        assert_eq!(resp.status, Status::Ok);
+        assert_eq!(Data::from_bytes(&resp.data[Transaction::UINT_SIZE()..]).unwrap(), Data::from("tim"));
+        drop(engine);
+
+        //a logged transaction that is refused on replay fails the load instead of being skipped
+        let disk = Disk::new(path.as_str(), DiskFormat::Bin, 0, Durability::Always, 0);
+        disk.log_transaction(&Transaction::new(Command::Set, 99, "name".to_owned(), Data::from("tim"), 0)).unwrap();
+        assert!(Engine::load(disk).is_err());
+
+        //a cycle left in a space from before they were refused reads as null where it closes
+        let _ = fs::remove_file(path.as_str());
+        let mut space = Space::new();
+        let obj = space.create();
+        if let Some(Node::Entity(ent)) = space.nodes.get_mut(&obj) {
+            ent.props.insert("me".to_owned(), obj);
+        }
+        space.write_snapshot(fs::File::create(format!("{}.snapshot", path)).unwrap(), 0).unwrap();
+        let engine = Engine::load(Disk::new(path.as_str(), DiskFormat::Bin, 0, Durability::Always, 0)).unwrap();
+        let resp = engine.execute(Transaction::new(Command::Get, obj, String::new(), Data::Null, 0));
+        assert_eq!(Data::from_bytes(&resp.data[Transaction::UINT_SIZE()..]).unwrap(), Data::from(r#"{"me":null}"#));
+
+        let _ = fs::remove_file(path.as_str());
+        let _ = fs::remove_file(format!("{}.snapshot", path));
+    }
+}
@@ -1,28 +1,74 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
 use std::thread::sleep;
 
 use sequencetree::SequenceTree;
 
-#[derive(Debug)]
+use crate::utils::{Data, DbError};
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Entity {
     pub props: HashMap<String, usize>
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Value {
-    pub val: String
+    pub val: Data
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct List {
+    pub items: Vec<usize>
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub enum Node {
     Entity(Entity),
-    Value(Value)
+    Value(Value),
+    List(List)
+}
+
+//what nodes looked like before a batch first touched them, so a failed batch can be taken back
+struct Journal {
+    id_cnt: usize,
+    nodes: HashMap<usize, (Option<Node>, bool)>,
+    //link counts as they were before the batch first changed them
+    refs: HashMap<usize, usize>,
+    //reverse index entries the batch replaced, along with the id they held
+    reverse: HashMap<String, usize>
+}
+
+//everything needed to rebuild a space without replaying the database file up to log_offset,
+//the reverse index is left out since it is rebuilt from the values
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+    log_offset: u64,
+    id_cnt: usize,
+    roots: &'a HashSet<usize>,
+    nodes: &'a HashMap<usize, Node>
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+    log_offset: u64,
+    id_cnt: usize,
+    roots: HashSet<usize>,
+    nodes: HashMap<usize, Node>
 }
 
 pub struct Space {
     pub nodes: HashMap<usize, Node>,
     pub reverse: SequenceTree<char, usize>,
-    id_cnt: usize
+    //objects and lists made by create that have not been linked under another node, gc keeps whatever they reach
+    pub roots: HashSet<usize>,
+    //how many object keys and list items point at each node, nodes nothing points at are left out
+    refs: HashMap<usize, usize>,
+    id_cnt: usize,
+    journal: Option<Journal>,
+    //ids handed out since take_allocated was last called, logged with the transaction that took them
+    allocated: Vec<usize>,
+    //while replaying, the ids the transaction was logged with that have not been handed out again yet
+    expected: Vec<usize>
 }
 
 impl Space {
@@ -30,70 +76,458 @@ impl Space {
         Space {
             nodes: HashMap::new(),
             reverse: SequenceTree::new(),
-            id_cnt: 0
+            roots: HashSet::new(),
+            refs: HashMap::new(),
+            id_cnt: 0,
+            journal: None,
+            allocated: vec![],
+            expected: vec![]
+        }
+    }
+
+    //makes the next ids handed out the given ones, so a replayed transaction gives its nodes the ids they had
+    //when it was logged no matter how ids would be picked today
+    pub fn replay_ids(&mut self, ids: &[usize]) {
+        self.expected = ids.iter().rev().cloned().collect();
+    }
+
+    pub fn take_allocated(&mut self) -> Vec<usize> {
+        self.expected.clear();
+        std::mem::replace(&mut self.allocated, vec![])
+    }
+
+    pub fn write_snapshot<W: Write>(&self, writer: W, log_offset: u64) -> Result<(), String> {
+        let snapshot = SnapshotRef { log_offset, id_cnt: self.id_cnt, roots: &self.roots, nodes: &self.nodes };
+        serde_json::to_writer(writer, &snapshot).map_err(|e| format!("{}", e))
+    }
+
+    //the space a snapshot holds and the offset of the database file it was taken at
+    pub fn read_snapshot<R: Read>(reader: R) -> Result<(Space, u64), String> {
+        let snapshot: Snapshot = serde_json::from_reader(reader).map_err(|e| format!("{}", e))?;
+
+        let mut space = Space::new();
+        space.nodes = snapshot.nodes;
+        space.roots = snapshot.roots;
+        space.id_cnt = snapshot.id_cnt;
+        space.reindex();
+        space.recount();
+
+        Ok((space, snapshot.log_offset))
+    }
+
+    //starts recording changes so they can be kept with commit or undone with rollback
+    pub fn begin(&mut self) {
+        self.journal = Some(Journal { id_cnt: self.id_cnt, nodes: HashMap::new(), refs: HashMap::new(), reverse: HashMap::new() });
+    }
+
+    pub fn commit(&mut self) {
+        self.journal = None;
+    }
+
+    pub fn rollback(&mut self) {
+        let journal = match self.journal.take() {
+            Some(j) => j,
+            None => return
+        };
+
+        for (id, (node, root)) in journal.nodes {
+            match node {
+                Some(n) => { self.nodes.insert(id, n); },
+                None => { self.nodes.remove(&id); }
+            };
+
+            if root {
+                self.roots.insert(id);
+            }else {
+                self.roots.remove(&id);
+            }
+        }
+        for (id, cnt) in journal.refs {
+            if cnt == 0 {
+                self.refs.remove(&id);
+            }else {
+                self.refs.insert(id, cnt);
+            }
+        }
+        self.id_cnt = journal.id_cnt;
+
+        //entries of values made during the batch stay behind until the next gc, upsert_prop does not trust them
+        for (tagged, id) in journal.reverse {
+            self.reverse.set(tagged.chars().collect(), id);
+        }
+    }
+
+    //remembers the node as it was before its first change since begin
+    fn touch(&mut self, id: usize) {
+        if let Some(journal) = self.journal.as_mut() {
+            if !journal.nodes.contains_key(&id) {
+                journal.nodes.insert(id, (self.nodes.get(&id).cloned(), self.roots.contains(&id)));
+            }
+        }
+    }
+
+    //counts one more link into the node
+    fn add_ref(&mut self, id: usize) {
+        self.touch_refs(id);
+        *self.refs.entry(id).or_insert(0) += 1;
+    }
+
+    fn drop_ref(&mut self, id: usize) {
+        self.touch_refs(id);
+        match self.refs.get_mut(&id) {
+            Some(cnt) if *cnt > 1 => *cnt -= 1,
+            _ => { self.refs.remove(&id); }
+        };
+    }
+
+    fn touch_refs(&mut self, id: usize) {
+        let cnt = self.refs.get(&id).cloned().unwrap_or(0);
+        if let Some(journal) = self.journal.as_mut() {
+            journal.refs.entry(id).or_insert(cnt);
         }
     }
 
     fn gen_id(&mut self) -> usize {
-        self.id_cnt += 1;
-        self.id_cnt
+        //an expected id that is already taken falls back to a new one, the caller finds out through take_allocated
+        let id = match self.expected.pop() {
+            Some(id) if !self.nodes.contains_key(&id) => id,
+            _ => self.id_cnt + 1
+        };
+
+        if id > self.id_cnt {
+            self.id_cnt = id;
+        }
+        self.allocated.push(id);
+
+        id
     }
 
     pub fn create(&mut self) -> usize {
 
         let id = self.gen_id();
         //println!("created object with id {}",id);
+        self.touch(id);
         self.nodes.insert(id, Node::Entity( Entity { props: HashMap::new() } ) );
+        self.roots.insert(id);
+
+        id
+    }
+
+    pub fn create_list(&mut self) -> usize {
+        let id = self.gen_id();
+        self.touch(id);
+        self.nodes.insert(id, Node::List( List { items: vec![] } ) );
+        self.roots.insert(id);
 
         id
     }
 
-    fn create_prop(&mut self, val: String) -> usize {
+    fn create_prop(&mut self, val: Data) -> usize {
         let id = self.gen_id();
 
-        self.reverse.set(val.chars().collect(), id);
+        self.insert_prop(id, val)
+    }
+
+    fn insert_prop(&mut self, id: usize, val: Data) -> usize {
+        self.touch(id);
+        let tagged = val.to_tagged();
+        if let Some(journal) = self.journal.as_mut() {
+            if let Some(replaced) = self.reverse.get(tagged.chars().collect()) {
+                journal.reverse.entry(tagged.to_owned()).or_insert(replaced.to_owned());
+            }
+        }
+        self.reverse.set(tagged.chars().collect(), id);
         self.nodes.insert(id, Node::Value( Value { val } ) );
 
         id
     }
 
-    fn upsert_prop(&mut self, value: &str) -> usize {
-        match self.reverse.get(value.chars().collect()) {
-            Some(id) => id.to_owned(),
-            None => self.create_prop(value.to_string())
+    fn upsert_prop(&mut self, value: &Data) -> usize {
+        //an entry left by a batch that was rolled back can point at an id that is gone or was handed out again
+        let tagged = value.to_tagged();
+        let found = match self.reverse.get(tagged.chars().collect()) {
+            Some(id) => match self.nodes.get(id) {
+                Some(Node::Value(v)) if v.val.to_tagged() == tagged => Some(id.to_owned()),
+                _ => None
+            },
+            None => None
+        };
+
+        match found {
+            Some(id) => id,
+            None => self.create_prop(value.to_owned())
+        }
+    }
+
+    fn index(obj: usize, key: &str, len: usize) -> Result<usize, DbError> {
+        match key.parse::<usize>() {
+            Ok(i) if i < len => Ok(i),
+            _ => Err(DbError::BadIndex(obj, key.to_owned()))
+        }
+    }
+
+    //makes sure the key of an object, or the index of a list, can be written before anything is changed
+    //so a rejected write leaves no trace. inserting allows the index right after the last item of a list
+    fn check_slot(&self, obj: usize, key: &str, inserting: bool) -> Result<(), DbError> {
+        match self.nodes.get(&obj) {
+            //an empty key stands for the object itself and a dot splits a path, neither could be read back
+            Some(Node::Entity(_)) if key.is_empty() || key.contains('.') => Err(DbError::BadKey(key.to_owned())),
+            Some(Node::Entity(_)) => Ok(()),
+            Some(Node::List(list)) => {
+                let len = if inserting { list.items.len() + 1 } else { list.items.len() };
+                Space::index(obj, key, len).map(|_| ())
+            },
+            Some(Node::Value(_)) => Err(DbError::NotAnObject(obj)),
+            None => Err(DbError::NoSuchObject(obj))
         }
     }
 
-    pub fn set(&mut self, obj: usize, key: &str, value: &str) {
+    //points the key of an object, or the index of a list, at a node
+    fn put(&mut self, obj: usize, key: &str, id: usize) -> Result<(), DbError> {
+        self.check_slot(obj, key, false)?;
+
+        self.touch(obj);
+        let replaced = match self.nodes.get_mut(&obj) {
+            Some(Node::Entity(ent)) => ent.props.insert(key.to_string(), id),
+            Some(Node::List(list)) => {
+                let i = Space::index(obj, key, list.items.len())?;
+                Some(std::mem::replace(&mut list.items[i], id))
+            },
+            _ => None
+        };
+
+        self.add_ref(id);
+        if let Some(old) = replaced {
+            self.drop_ref(old);
+        }
+        Ok(())
+    }
+
+    //removes the key of an object, or the index of a list, and returns the node it pointed at
+    fn take(&mut self, obj: usize, key: &str) -> Result<Option<usize>, DbError> {
+        self.check_slot(obj, key, false)?;
+
+        self.touch(obj);
+        let taken = match self.nodes.get_mut(&obj) {
+            Some(Node::Entity(ent)) => ent.props.remove(key),
+            Some(Node::List(list)) => {
+                let i = Space::index(obj, key, list.items.len())?;
+                Some(list.items.remove(i))
+            },
+            _ => None
+        };
+
+        if let Some(id) = taken {
+            self.drop_ref(id);
+        }
+        Ok(taken)
+    }
+
+    fn list_mut(&mut self, obj: usize) -> Result<&mut List, DbError> {
+        self.touch(obj);
+        match self.nodes.get_mut(&obj) {
+            Some(Node::List(list)) => Ok(list),
+            Some(_) => Err(DbError::NotAList(obj)),
+            None => Err(DbError::NoSuchObject(obj))
+        }
+    }
+
+    //the node to add to a list, other_node when given otherwise the value
+    fn item(&mut self, list: usize, value: &Data, other_node: usize) -> Result<usize, DbError> {
+        if other_node == 0 {
+            return Ok(self.upsert_prop(value));
+        }
+
+        self.check_link(list, other_node)?;
+        self.touch(other_node);
+        self.roots.remove(&other_node);
+        Ok(other_node)
+    }
+
+    pub fn set(&mut self, obj: usize, key: &str, value: &Data) -> Result<(), DbError> {
         //println!("set {} to obj {} by key {}", value, obj, key);
+        self.check_slot(obj, key, false)?;
         let prop = self.upsert_prop(value);
 
-        let mut node = match self.nodes.get_mut(&obj) {
-            Some(node) => node,
-            None => panic!("No such object ({})", obj)
+        self.put(obj, key, prop)
+    }
+
+    //adds to (or takes from when sub is set) the number at the key, a key that does not exist yet counts from 0.
+    //returns the id and value of the resulting number
+    pub fn incr(&mut self, obj: usize, key: &str, by: &Data, sub: bool) -> Result<(usize, Data), DbError> {
+        self.check_slot(obj, key, false)?;
+
+        let current = match self.get(obj, key) {
+            Some((_, Node::Value(v))) if v.val.is_number() => v.val.to_owned(),
+            Some((_, Node::Value(Value { val: Data::Null }))) | None => Data::U64(0),
+            _ => return Err(DbError::NotANumber(format!("Key {}", key)))
         };
-        //println!("{:?}",node);
-        let ent = match node {
-            Node::Entity(ent) => ent,
-            Node::Value(val) => panic!("Id {} links to a value ({})", obj, val.val)
+
+        let by = match by {
+            Data::Null => Data::U64(1),
+            n if n.is_number() => n.to_owned(),
+            _ => return Err(DbError::NotANumber(format!("The amount {}", by)))
         };
 
-        ent.props.insert(key.to_string(), prop);
+        let val = match current.add(&by, sub) {
+            Some(n) => n,
+            None => return Err(DbError::Overflow(key.to_owned()))
+        };
+        let prop = self.upsert_prop(&val);
+        self.put(obj, key, prop)?;
+
+        Ok((prop, val))
     }
 
-    pub fn link(&mut self, obj: usize, key: &str, other_node: usize) {
+    pub fn link(&mut self, obj: usize, key: &str, other_node: usize) -> Result<(), DbError> {
         //println!("link {} to obj {} by key {}", other_node, obj, key);
-        let mut node = match self.nodes.get_mut(&obj) {
-            Some(node) => node,
-            None => panic!("No such object ({})", obj)
-        };
+        self.check_link(obj, other_node)?;
+        self.put(obj, key, other_node)?;
+        self.touch(other_node);
+        self.roots.remove(&other_node);
 
-        let ent = match node {
-            Node::Entity(ent) => ent,
-            Node::Value(val) => panic!("Id {} links to a value ({})", obj, val.val)
-        };
+        Ok(())
+    }
+
+    //a link has to point at a node that exists, and cannot point back up at the node it is put under. reading an
+    //object follows its links all the way down and gc keeps nodes alive through them
+    fn check_link(&self, obj: usize, other_node: usize) -> Result<(), DbError> {
+        if !self.nodes.contains_key(&other_node) {
+            return Err(DbError::NoSuchObject(other_node));
+        }
+
+        let mut seen: HashSet<usize> = HashSet::new();
+        let mut stack = vec![other_node];
+        while let Some(id) = stack.pop() {
+            if id == obj {
+                return Err(DbError::Cycle(other_node, obj));
+            }
+            if !seen.insert(id) {
+                continue;
+            }
+
+            match self.nodes.get(&id) {
+                Some(Node::Entity(ent)) => stack.extend(ent.props.values()),
+                Some(Node::List(list)) => stack.extend(list.items.iter()),
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
 
-        ent.props.insert(key.to_string(), other_node);
+    pub fn delete(&mut self, obj: usize, key: &str) -> Result<(), DbError> {
+        if key.len() == 0 {
+            //values are shared through the reverse index and other nodes may still link to an object or list,
+            //removing either would leave ids pointing at nothing
+            match self.nodes.get(&obj) {
+                Some(Node::Value(_)) => return Err(DbError::NotAnObject(obj)),
+                Some(_) => {},
+                None => return Err(DbError::NoSuchObject(obj))
+            };
+            if let Some(cnt) = self.refs.get(&obj) {
+                return Err(DbError::StillLinked(obj, cnt.to_owned()));
+            }
+
+            self.touch(obj);
+            self.roots.remove(&obj);
+            let children = match self.nodes.remove(&obj) {
+                Some(Node::Entity(ent)) => ent.props.values().cloned().collect(),
+                Some(Node::List(list)) => list.items,
+                _ => vec![]
+            };
+            for child in children {
+                self.drop_ref(child);
+            }
+            return Ok(());
+        }
+
+        //the node the key pointed at may be linked from elsewhere too, gc reclaims it once nothing reaches it
+        self.take(obj, key)?;
+
+        Ok(())
+    }
+
+    pub fn unlink(&mut self, obj: usize, key: &str) -> Result<(), DbError> {
+        self.take(obj, key)?;
+
+        Ok(())
+    }
+
+    pub fn append(&mut self, list: usize, value: &Data, other_node: usize) -> Result<(), DbError> {
+        self.list_mut(list)?;
+
+        let item = self.item(list, value, other_node)?;
+        self.list_mut(list)?.items.push(item);
+        self.add_ref(item);
+
+        Ok(())
+    }
+
+    pub fn insert_at(&mut self, list: usize, key: &str, value: &Data, other_node: usize) -> Result<(), DbError> {
+        self.list_mut(list)?;
+        self.check_slot(list, key, true)?;
+
+        let item = self.item(list, value, other_node)?;
+        let list_node = self.list_mut(list)?;
+        let i = Space::index(list, key, list_node.items.len() + 1)?;
+        list_node.items.insert(i, item);
+        self.add_ref(item);
+
+        Ok(())
+    }
+
+    pub fn remove_at(&mut self, list: usize, key: &str) -> Result<(), DbError> {
+        let list_node = self.list_mut(list)?;
+        let i = Space::index(list, key, list_node.items.len())?;
+        let item = list_node.items.remove(i);
+        self.drop_ref(item);
+
+        Ok(())
+    }
+
+    //builds the objects, lists and values of a json document and returns the id of its root. The keys are checked
+    //before anything is made so a bad one leaves nothing behind
+    pub fn put_json(&mut self, json: &serde_json::Value) -> Result<usize, DbError> {
+        Space::check_keys(json)?;
+        self.build_json(json)
+    }
+
+    fn check_keys(json: &serde_json::Value) -> Result<(), DbError> {
+        match json {
+            serde_json::Value::Object(map) => map.iter().try_for_each(|(key, val)| {
+                if key.is_empty() || key.contains('.') {
+                    return Err(DbError::BadKey(key.to_owned()));
+                }
+                Space::check_keys(val)
+            }),
+            serde_json::Value::Array(arr) => arr.iter().try_for_each(Space::check_keys),
+            _ => Ok(())
+        }
+    }
+
+    fn build_json(&mut self, json: &serde_json::Value) -> Result<usize, DbError> {
+        match json {
+            serde_json::Value::Object(map) => {
+                let obj = self.create();
+                for (key, val) in map {
+                    let prop = self.build_json(val)?;
+                    self.link(obj, key, prop)?;
+                }
+
+                Ok(obj)
+            },
+            serde_json::Value::Array(arr) => {
+                let list = self.create_list();
+                for val in arr {
+                    let item = self.build_json(val)?;
+                    self.append(list, &Data::Null, item)?;
+                }
+
+                Ok(list)
+            },
+            val => Ok(self.upsert_prop(&Data::from(val)))
+        }
     }
 
     pub fn get(&self, obj: usize, key: &str) -> Option<(usize, &Node)> {
@@ -112,6 +546,15 @@ impl Space {
                                 None => None
                             }
                         },
+                        Node::List(list) => {
+                            match key.parse::<usize>().ok().and_then(|i| list.items.get(i)) {
+                                Some(id) => match self.nodes.get(id) {
+                                    Some(n) => Some((id.to_owned(), n)),
+                                    None => None
+                                },
+                                None => None
+                            }
+                        },
                         Node::Value(_) => None
                     }
                 }
@@ -119,4 +562,183 @@ impl Space {
             None => None
         }
     }
-}
\ No newline at end of file
+
+    pub fn last_id(&self) -> usize {
+        self.id_cnt
+    }
+
+    pub fn gc(&mut self) -> usize {
+        let mut marked: HashSet<usize> = HashSet::new();
+        let mut stack: Vec<usize> = self.roots.iter().cloned().collect();
+
+        while let Some(id) = stack.pop() {
+            if !marked.insert(id) {
+                continue;
+            }
+
+            match self.nodes.get(&id) {
+                Some(Node::Entity(ent)) => stack.extend(ent.props.values()),
+                Some(Node::List(list)) => stack.extend(list.items.iter()),
+                _ => {}
+            }
+        }
+
+        let before = self.nodes.len();
+        self.nodes.retain(|id, _| marked.contains(id));
+
+        //the reverse index may still point at reclaimed values so it is rebuilt from the survivors, so are the
+        //link counts since reclaimed nodes no longer link to anything
+        self.reindex();
+        self.recount();
+
+        before - self.nodes.len()
+    }
+
+    fn recount(&mut self) {
+        self.refs = HashMap::new();
+        for node in self.nodes.values() {
+            let links: Vec<usize> = match node {
+                Node::Entity(ent) => ent.props.values().cloned().collect(),
+                Node::List(list) => list.items.clone(),
+                Node::Value(_) => vec![]
+            };
+            for id in links {
+                *self.refs.entry(id).or_insert(0) += 1;
+            }
+        }
+    }
+
+    fn reindex(&mut self) {
+        self.reverse = SequenceTree::new();
+        for (id, node) in &self.nodes {
+            if let Node::Value(v) = node {
+                self.reverse.set(v.val.to_tagged().chars().collect(), id.to_owned());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{Transaction, Command};
+    use serde_json::json;
+
+    #[test]
+    fn gc() {
+        let mut space = Space::new();
+        let obj = space.create();
+        let sub_obj = space.create();
+
+        space.set(sub_obj, "age", &Data::U64(55)).unwrap();
+        space.link(obj, "child", sub_obj).unwrap();
+        space.set(sub_obj, "age", &Data::U64(56)).unwrap();
+        assert_eq!(space.gc(), 1);
+
+        space.unlink(obj, "child").unwrap();
+        assert_eq!(space.gc(), 2);
+        assert_eq!(space.nodes.len(), 1);
+
+        //an object can be dropped by id only once nothing links to it, a failed batch puts the links back
+        let list = space.create_list();
+        let sub_obj = space.create();
+        space.link(obj, "child", sub_obj).unwrap();
+        space.append(list, &Data::Null, sub_obj).unwrap();
+        space.unlink(obj, "child").unwrap();
+        assert_eq!(space.delete(sub_obj, ""), Err(DbError::StillLinked(sub_obj, 1)));
+        space.begin();
+        space.remove_at(list, "0").unwrap();
+        space.rollback();
+        assert_eq!(space.delete(sub_obj, ""), Err(DbError::StillLinked(sub_obj, 1)));
+        space.remove_at(list, "0").unwrap();
+        assert_eq!(space.delete(sub_obj, ""), Ok(()));
+    }
+
+    #[test]
+    fn rollback() {
+        let mut space = Space::new();
+        let obj = space.create();
+        space.set(obj, "age", &Data::U64(55)).unwrap();
+
+        space.begin();
+        let sub_obj = space.create();
+        space.set(sub_obj, "name", &Data::from("tim")).unwrap();
+        space.link(obj, "child", sub_obj).unwrap();
+        space.delete(obj, "age").unwrap();
+        space.rollback();
+
+        assert_eq!(space.nodes.len(), 2);
+        assert_eq!(space.last_id(), 2);
+        assert!(space.get(obj, "child").is_none());
+        assert!(space.get(obj, "age").is_some());
+        assert_eq!(space.roots.len(), 1);
+
+        //the id of a value made by the rolled back batch is handed out again, the value is not found through it
+        space.create();
+        let reused = space.create();
+        space.set(obj, "name", &Data::from("tim")).unwrap();
+        assert!(space.get(obj, "name").unwrap().0 != reused);
+        assert_eq!(space.get(reused, "").unwrap().0, reused);
+    }
+
+    #[test]
+    fn incr() {
+        let mut space = Space::new();
+        let obj = space.create();
+        space.set(obj, "name", &Data::from("tim")).unwrap();
+        assert!(space.incr(obj, "name", &Data::Null, false).is_err());
+        space.set(obj, "max", &Data::U64(u64::max_value())).unwrap();
+        assert_eq!(space.incr(obj, "max", &Data::Null, false), Err(DbError::Overflow("max".to_owned())));
+    }
+
+    #[test]
+    fn errors() {
+        let mut space = Space::new();
+        let obj = space.create();
+        let list = space.create_list();
+
+        assert_eq!(space.set(obj + 10, "age", &Data::U64(55)), Err(DbError::NoSuchObject(obj + 10)));
+        assert_eq!(space.append(obj, &Data::U64(55), 0), Err(DbError::NotAList(obj)));
+        assert_eq!(space.remove_at(list, "0"), Err(DbError::BadIndex(list, "0".to_owned())));
+        assert_eq!(space.append(list, &Data::Null, usize::MAX), Err(DbError::NoSuchObject(usize::MAX)));
+        assert_eq!(space.insert_at(list, "0", &Data::Null, obj + 10), Err(DbError::NoSuchObject(obj + 10)));
+        assert_eq!(space.nodes.len(), 2);
+
+        //links only point at nodes that exist and never back up
+        assert_eq!(space.link(obj, "next", obj + 10), Err(DbError::NoSuchObject(obj + 10)));
+        assert_eq!(space.link(obj, "me", obj), Err(DbError::Cycle(obj, obj)));
+        space.link(obj, "items", list).unwrap();
+        assert_eq!(space.append(list, &Data::Null, obj), Err(DbError::Cycle(obj, list)));
+        assert_eq!(space.append(list, &Data::Null, list), Err(DbError::Cycle(list, list)));
+        space.unlink(obj, "items").unwrap();
+
+        //keys that could never be read back are refused, for a document before any of it is made
+        assert_eq!(space.set(obj, "", &Data::U64(55)), Err(DbError::BadKey(String::new())));
+        assert_eq!(space.link(obj, "a.b", list), Err(DbError::BadKey("a.b".to_owned())));
+        let doc = json!({"name": "tim", "stats": [{"clicks.total": 3}]});
+        assert_eq!(space.put_json(&doc), Err(DbError::BadKey("clicks.total".to_owned())));
+        assert_eq!(space.nodes.len(), 2);
+    }
+
+    #[test]
+    fn logged_ids() {
+        let mut space = Space::new();
+        let obj = space.create();
+        space.set(obj, "name", &Data::from("tim")).unwrap();
+        assert_eq!(space.take_allocated(), vec![1, 2]);
+
+        //a replayed transaction gets the ids it was logged with, whatever the counter says
+        let mut replayed = Space::new();
+        replayed.replay_ids(&[5]);
+        assert_eq!(replayed.create(), 5);
+        replayed.replay_ids(&[9]);
+        replayed.set(5, "name", &Data::from("tim")).unwrap();
+        assert_eq!(replayed.take_allocated(), vec![5, 9]);
+        assert_eq!(replayed.create(), 10);
+
+        let mut t = Transaction::new(Command::Create, 0, String::new(), Data::Null, 0);
+        assert_eq!(Transaction::from(t.to_bytes()).unwrap().ids, None);
+        t.ids = Some(vec![5, 9]);
+        assert_eq!(Transaction::from(t.to_bytes()).unwrap().ids, Some(vec![5, 9]));
+    }
+}